@@ -0,0 +1,126 @@
+// Tell the operator the pool of addresses is running low while there's still
+// time to generate another batch offline, before visitors get turned away.
+
+use std::{fs::OpenOptions, io::Write, path::PathBuf};
+
+use anyhow::Result;
+use chrono::Local;
+use serde_json::json;
+
+use crate::{webhook::Webhook, PoolLevel, DEFAULT_LOW_WATER};
+
+/// Where to send word that the pool is low
+#[derive(Debug, Clone)]
+pub struct RefillAlert {
+    pub low_water: u32,
+    /// POST a `pool.low` event here
+    pub webhook: Option<Webhook>,
+    /// Append an email-style message here, for a mail spool or whatever's watching it
+    pub file: Option<PathBuf>,
+}
+
+impl Default for RefillAlert {
+    fn default() -> Self {
+        Self {
+            low_water: DEFAULT_LOW_WATER,
+            webhook: None,
+            file: None,
+        }
+    }
+}
+
+impl RefillAlert {
+    /// Send the alert through every configured hook
+    pub fn fire(&self, database: &str, level: &PoolLevel) -> Result<()> {
+        let message = refill_message(database, level);
+
+        if let Some(webhook) = &self.webhook {
+            webhook.send(&json!({
+                "event": "pool.low",
+                "database": database,
+                "unused": level.unused,
+                "low_water": level.low_water,
+                "message": message,
+            }))?;
+        }
+
+        if let Some(path) = &self.file {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(
+                file,
+                "Date: {}\nSubject: Address Factory: {} addresses left in {}\n\n{}\n",
+                Local::now().to_rfc2822(),
+                level.unused,
+                database,
+                message
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn refill_message(database: &str, level: &PoolLevel) -> String {
+    let left = match level.unused {
+        0 => format!("{} has no unused addresses left.", database),
+        unused => format!(
+            "{} has {} unused addresses left, below the low-water mark of {}.",
+            database, unused, level.low_water
+        ),
+    };
+
+    format!(
+        "{} Run address-factory on your offline machine with --append to generate another batch into a copy of it, then deploy that.",
+        left
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use anyhow::Result;
+    use serde_json::Value;
+
+    use super::RefillAlert;
+    use crate::{
+        webhook::{mock_receiver, Webhook},
+        PoolLevel,
+    };
+
+    #[test]
+    fn fires_every_hook() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (url, received) = mock_receiver(0);
+        let mut webhook = Webhook::new(&url);
+        webhook.backoff = Duration::from_millis(1);
+        let alert = RefillAlert {
+            low_water: 10,
+            webhook: Some(webhook),
+            file: Some(dir.path().join("alerts.mail")),
+        };
+        let level = PoolLevel {
+            unused: 3,
+            low_water: 10,
+            low: true,
+        };
+
+        alert.fire("ours.db", &level)?;
+        alert.fire("ours.db", &level)?;
+
+        let mail = fs::read_to_string(dir.path().join("alerts.mail"))?;
+        assert_eq!(
+            mail.matches("Subject: Address Factory: 3 addresses left in ours.db")
+                .count(),
+            2
+        );
+        assert!(mail.contains("below the low-water mark of 10"));
+
+        let received = received.lock().unwrap();
+        let event: Value = serde_json::from_str(&received[0].1)?;
+        assert_eq!(event["event"], "pool.low");
+        assert_eq!(event["unused"], 3);
+
+        Ok(())
+    }
+}
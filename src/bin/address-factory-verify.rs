@@ -0,0 +1,31 @@
+use address_factory::{verify_signatures, Database, Keyring};
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+
+use clap::Clap;
+
+/// Check a signed addresses database before serving it. Only needs the signer's
+/// public key, so it's safe to run on the server where the xpub isn't.
+#[derive(Clap)]
+#[clap(version = "0.1 Alpha", author = "Paul M. <paul@paul.lol>")]
+struct Opts {
+    database: PathBuf,
+    /// Exported public key of the signer (gpg --armor --export <key ID>)
+    #[clap(long)]
+    public_key: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let opts: Opts = Opts::parse();
+
+    let db = Database::open_read_only(&opts.database)?;
+    let keyring = Keyring::from_public_key(&opts.public_key)?;
+
+    let report = verify_signatures(&db, &keyring)?;
+    println!("{}", report);
+
+    if !report.is_ok() {
+        bail!("{} failed verification", db.filename)
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+// BIP21 payment URIs: https://github.com/bitcoin/bips/blob/master/bip-0021.mediawiki
+
+use bdk::bitcoin::{util::amount::Denomination, Amount};
+
+const SCHEME: &str = "bitcoin:";
+
+/// The optional parts of a payment URI
+#[derive(Debug, Clone, Default)]
+pub struct PaymentRequest {
+    /// Who's being paid, e.g. "Satoshi Nakamoto"
+    pub label: Option<String>,
+    /// What the payment is for
+    pub message: Option<String>,
+    pub amount: Option<Amount>,
+}
+
+/// Build a `bitcoin:` URI for an address
+pub fn payment_uri(address: &str, request: &PaymentRequest) -> String {
+    let mut params = vec![];
+
+    if let Some(amount) = request.amount {
+        params.push(format!(
+            "amount={}",
+            amount.to_string_in(Denomination::Bitcoin)
+        ));
+    }
+    if let Some(label) = &request.label {
+        params.push(format!("label={}", percent_encode(label)));
+    }
+    if let Some(message) = &request.message {
+        params.push(format!("message={}", percent_encode(message)));
+    }
+
+    if params.is_empty() {
+        format!("bitcoin:{}", address)
+    } else {
+        format!("bitcoin:{}?{}", address, params.join("&"))
+    }
+}
+
+/// The address a `bitcoin:` URI pays, if it is one
+pub fn uri_address(uri: &str) -> Option<&str> {
+    let scheme = uri.get(..SCHEME.len())?;
+    if !scheme.eq_ignore_ascii_case(SCHEME) {
+        return None;
+    }
+    uri[SCHEME.len()..].split('?').next()
+}
+
+/// The amount a URI asks for, if it asks for one
+pub fn uri_amount(uri: &str) -> Option<Amount> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix("amount="))
+        .and_then(|amount| Amount::from_str_in(amount, Denomination::Bitcoin).ok())
+}
+
+/// Percent-encode everything but the unreserved characters from RFC 3986
+pub fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk::bitcoin::Amount;
+
+    use super::{payment_uri, uri_address, uri_amount, PaymentRequest};
+
+    #[test]
+    fn builds_uris() {
+        let address = "tb1qc58ys2dphtphg6yuugdf3d0kufmk0tye044g3l";
+
+        assert_eq!(
+            payment_uri(address, &PaymentRequest::default()),
+            "bitcoin:tb1qc58ys2dphtphg6yuugdf3d0kufmk0tye044g3l"
+        );
+
+        let request = PaymentRequest {
+            label: Some("Luke-Jr & friends".to_string()),
+            message: Some("Donation".to_string()),
+            amount: Some(Amount::from_sat(50_000)),
+        };
+        assert_eq!(
+            payment_uri(address, &request),
+            "bitcoin:tb1qc58ys2dphtphg6yuugdf3d0kufmk0tye044g3l?amount=0.00050000&label=Luke-Jr%20%26%20friends&message=Donation"
+        );
+        assert_eq!(
+            uri_amount(&payment_uri(address, &request)),
+            Some(Amount::from_sat(50_000))
+        );
+        assert_eq!(
+            uri_amount("bitcoin:tb1qc58ys2dphtphg6yuugdf3d0kufmk0tye044g3l"),
+            None
+        );
+        assert_eq!(uri_address(&payment_uri(address, &request)), Some(address));
+        assert_eq!(uri_address(&format!("BITCOIN:{}", address)), Some(address));
+        assert_eq!(uri_address(address), None);
+    }
+}
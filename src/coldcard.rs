@@ -2,9 +2,8 @@ use anyhow::Result;
 use std::str::FromStr;
 
 use bdk::bitcoin::{
-    Network,
     util::bip32::{DerivationPath, ExtendedPubKey},
-    Address,
+    Address, Network,
 };
 use serde::Deserialize;
 
@@ -15,7 +14,9 @@ pub struct Bip84Json {
     _pub: String,
     pub deriv: String,
     pub first: String,
+    #[allow(dead_code)]
     name: String,
+    #[allow(dead_code)]
     xfp: String,
     pub xpub: String,
 }
@@ -24,8 +25,10 @@ pub struct Bip84Json {
 pub struct ColdcardJson {
     pub chain: String,
     pub xfp: String,
+    #[allow(dead_code)]
     xpub: String,
     // TODO: use the account, yes?
+    #[allow(dead_code)]
     account: u64,
     // TODO: use other address types?
     pub bip84: Bip84Json,
@@ -37,7 +40,7 @@ impl ColdcardJson {
         let network = match &self.chain[..] {
             "XTN" => Network::Testnet,
             "BTC" => Network::Bitcoin,
-            _ => panic!("Didn't expect that network")
+            _ => panic!("Didn't expect that network"),
         };
 
         Ok(network)
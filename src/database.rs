@@ -1,6 +1,64 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use chrono::Local;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row, TransactionBehavior};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use bdk::bitcoin::Network;
+
+use crate::{qr::QrFormat, util, webhook::PaymentEvent, Desc};
+
+/// Each migration takes the schema from one version to the next, starting at version 1.
+/// Add one here whenever the tables change rather than editing the CREATE statements,
+/// so databases already out on servers can be brought up to date.
+const MIGRATIONS: &[&str] = &[
+    // 2: dispensing addresses
+    "ALTER TABLE entries ADD COLUMN dispensed_at TEXT;
+     ALTER TABLE entries ADD COLUMN label TEXT;
+     ALTER TABLE entries ADD COLUMN paid_at TEXT;",
+    // 3: payment URIs and QR codes
+    "ALTER TABLE entries ADD COLUMN uri TEXT;
+     CREATE TABLE qr_codes (
+            entry_id        INTEGER NOT NULL REFERENCES entries(id),
+            format          TEXT NOT NULL,
+            image           BLOB NOT NULL,
+            PRIMARY KEY(entry_id, format)
+            );",
+    // 4: Merkle commitments
+    "ALTER TABLE batches ADD COLUMN merkle_root TEXT;
+     ALTER TABLE batches ADD COLUMN signed_root TEXT;
+     ALTER TABLE entries ADD COLUMN merkle_proof TEXT;",
+    // 5: payments seen by `watch`
+    "CREATE TABLE payments (
+            entry_id        INTEGER NOT NULL REFERENCES entries(id),
+            txid            TEXT NOT NULL,
+            amount          INTEGER NOT NULL,
+            height          INTEGER,
+            first_seen_at   TEXT NOT NULL,
+            PRIMARY KEY(entry_id, txid)
+            );",
+    // 6: webhook deliveries
+    "ALTER TABLE payments ADD COLUMN seen_notified_at TEXT;
+     ALTER TABLE payments ADD COLUMN confirmed_notified_at TEXT;",
+    // 7: message templates
+    "ALTER TABLE entries ADD COLUMN statement TEXT;",
+    // 8: validity windows
+    "ALTER TABLE batches ADD COLUMN valid_until TEXT;",
+];
+
+/// Warn once fewer than this many addresses are left to hand out
+pub const DEFAULT_LOW_WATER: u32 = 100;
+
+/// Addresses are derived at unhardened indexes, which stop short of 2^31
+const MAX_INDEX: u32 = 1 << 31;
+
+const SCHEMA_VERSION: i32 = MIGRATIONS.len() as i32 + 1;
+
+const ENTRY_COLUMNS: &str = "id, batch_id, derivation_index, address, message, dispensed_at, \
+    label, paid_at, uri, merkle_proof, statement";
+
+const BATCH_COLUMNS: &str = "id, descriptor_checksum, network, start_index, count, \
+    signing_key_fingerprint, created_at, tool_version, merkle_root, signed_root, valid_until";
 
 #[derive(Debug)]
 pub struct Database {
@@ -8,75 +66,1098 @@ pub struct Database {
     pub filename: String,
 }
 
-#[derive(Debug)]
+/// One run of the factory: a contiguous range of addresses signed by one key
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub id: i64,
+    pub descriptor_checksum: String,
+    pub network: String,
+    pub start_index: u32,
+    pub count: u32,
+    pub signing_key_fingerprint: String,
+    pub created_at: String,
+    pub tool_version: String,
+    /// Set when the batch is committed to by one signed Merkle root
+    /// instead of a signature per address
+    pub merkle_root: Option<String>,
+    /// The clearsigned statement holding the root
+    pub signed_root: Option<String>,
+    /// Last day the batch's addresses should be handed out, YYYY-MM-DD
+    pub valid_until: Option<String>,
+}
+
+impl Batch {
+    pub fn new(
+        descriptor: &Desc,
+        network: Network,
+        start_index: u32,
+        count: u32,
+        signing_key_fingerprint: &str,
+    ) -> Self {
+        Self {
+            id: 0,
+            descriptor_checksum: util::descriptor_checksum(descriptor),
+            network: network.to_string(),
+            start_index,
+            count,
+            signing_key_fingerprint: signing_key_fingerprint.to_string(),
+            created_at: Local::now().to_rfc3339(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            merkle_root: None,
+            signed_root: None,
+            valid_until: None,
+        }
+    }
+
+    /// One past the batch's last index, unless the range runs past the last
+    /// unhardened index, which only an edited database can do
+    pub fn end_index(&self) -> Option<u32> {
+        self.start_index
+            .checked_add(self.count)
+            .filter(|end| *end <= MAX_INDEX)
+    }
+
+    /// Whether the last day to hand out its addresses has passed
+    pub fn is_expired(&self) -> bool {
+        self.valid_until
+            .as_deref()
+            .is_some_and(|valid_until| valid_until < today().as_str())
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            descriptor_checksum: row.get(1)?,
+            network: row.get(2)?,
+            start_index: row.get(3)?,
+            count: row.get(4)?,
+            signing_key_fingerprint: row.get(5)?,
+            created_at: row.get(6)?,
+            tool_version: row.get(7)?,
+            merkle_root: row.get(8)?,
+            signed_root: row.get(9)?,
+            valid_until: row.get(10)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Entry {
-    id: i32,
-    address: String,
-    message: String,
+    pub id: i32,
+    pub batch_id: i64,
+    pub derivation_index: u32,
+    pub address: String,
+    pub message: String,
+    /// When the address was handed out, if it has been
+    pub dispensed_at: Option<String>,
+    /// Whatever the address was handed out for, e.g. an order id
+    pub label: Option<String>,
+    pub paid_at: Option<String>,
+    /// BIP21 payment URI, if the batch was generated with them
+    pub uri: Option<String>,
+    /// Proof the address is under its batch's Merkle root. In that mode
+    /// `message` is empty, there's no signature per address.
+    pub merkle_proof: Option<String>,
+    /// The factory's message rendered for this address, the text that was signed
+    pub statement: Option<String>,
+}
+
+/// A transaction paying one of our addresses
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Payment {
+    pub txid: String,
+    /// Satoshis this transaction sent to the address
+    pub amount: u64,
+    /// Block it confirmed in, `None` while it's in the mempool
+    pub height: Option<u32>,
+}
+
+/// How much of the address pool is left
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub total: u32,
+    pub unused: u32,
+    /// Handed out and still waiting on payment
+    pub dispensed: u32,
+    pub paid: u32,
+    /// Never handed out, and now past their batch's valid-until date
+    pub expired: u32,
+}
+
+/// Unused addresses left against the low-water mark
+#[derive(Debug, Serialize)]
+pub struct PoolLevel {
+    pub unused: u32,
+    pub low_water: u32,
+    /// Below the mark, or empty. Time to generate another batch.
+    pub low: bool,
 }
 
 impl Entry {
-    pub fn new(address: &str, signed_message: &str) -> Self {
+    pub fn new(batch_id: i64, derivation_index: u32, address: &str, signed_message: &str) -> Self {
         Self {
             id: 0,
+            batch_id,
+            derivation_index,
             address: address.to_string(),
             message: signed_message.to_string(),
+            dispensed_at: None,
+            label: None,
+            paid_at: None,
+            uri: None,
+            merkle_proof: None,
+            statement: None,
         }
     }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            batch_id: row.get(1)?,
+            derivation_index: row.get(2)?,
+            address: row.get(3)?,
+            message: row.get(4)?,
+            dispensed_at: row.get(5)?,
+            label: row.get(6)?,
+            paid_at: row.get(7)?,
+            uri: row.get(8)?,
+            merkle_proof: row.get(9)?,
+            statement: row.get(10)?,
+        })
+    }
 }
 
 impl Database {
-    // Create SQLite database of addresses & signed messages
-
-    pub fn new() -> Result<Self> {
+    /// Pick a fresh `<timestamp>_signed_addresses.db` name in `dir`.
+    /// Two runs in the same second get a numbered suffix instead of colliding.
+    pub fn default_path(dir: &Path) -> PathBuf {
         let date_time = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-        let filename = format!("{}_signed_addresses.db", date_time);
+        let mut path = dir.join(format!("{}_signed_addresses.db", date_time));
 
-        // TODO: fail gracefully if the filename already exists
-        let connection = match Connection::open(filename.clone()) {
+        let mut n = 1;
+        while path.exists() {
+            n += 1;
+            path = dir.join(format!("{}_{}_signed_addresses.db", date_time, n));
+        }
+
+        path
+    }
+
+    /// Create SQLite database of addresses & signed messages
+    pub fn create(path: &Path) -> Result<Self> {
+        if path.exists() {
+            bail!(
+                "{} already exists. Pick another path or append to it instead.",
+                path.to_string_lossy()
+            )
+        }
+
+        let connection = match Connection::open(path) {
             Ok(conn) => conn,
             Err(error) => return Err(anyhow!("Not able to create database file: {}", error)),
         };
+        Self::configure(&connection)?;
+
+        connection.execute(
+            "CREATE TABLE batches (
+                  id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                  descriptor_checksum     TEXT NOT NULL,
+                  network                 TEXT NOT NULL,
+                  start_index             INTEGER NOT NULL,
+                  count                   INTEGER NOT NULL,
+                  signing_key_fingerprint TEXT NOT NULL,
+                  created_at              TEXT NOT NULL,
+                  tool_version            TEXT NOT NULL
+                  )",
+            params![],
+        )?;
 
         connection.execute(
             "CREATE TABLE entries (
                   id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                  address         TEXT NOT NULL,
-                  message         TEXT NOT NULL
+                  batch_id        INTEGER NOT NULL REFERENCES batches(id),
+                  derivation_index INTEGER NOT NULL,
+                  address         TEXT NOT NULL UNIQUE,
+                  message         TEXT NOT NULL,
+                  UNIQUE(batch_id, derivation_index)
                   )",
             params![],
         )?;
-        Ok(Self {
+
+        connection.execute_batch("PRAGMA user_version = 1")?;
+
+        let db = Self {
             connection,
-            filename,
-        })
+            filename: path.to_string_lossy().to_string(),
+        };
+        db.migrate()?;
+
+        Ok(db)
+    }
+
+    /// Open an existing database of signed addresses
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = match Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+        {
+            Ok(conn) => conn,
+            Err(error) => return Err(anyhow!("Not able to open database file: {}", error)),
+        };
+        Self::configure(&connection)?;
+
+        let db = Self {
+            connection,
+            filename: path.to_string_lossy().to_string(),
+        };
+        db.check_schema()?;
+        db.migrate()?;
+
+        Ok(db)
+    }
+
+    /// Settings that don't stick to the file and have to be set on every connection.
+    /// WAL lets big batches write without blocking `serve` reading the same file.
+    fn configure(connection: &Connection) -> Result<()> {
+        connection.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;",
+        )?;
+        Ok(())
+    }
+
+    /// Open a database just to read it, for checking or exporting. Unlike `open`
+    /// it never writes to the file, so an older database has to be upgraded first.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let connection = match Self::connect_read_only(path) {
+            Ok(conn) => conn,
+            Err(error) => return Err(anyhow!("Not able to open database file: {}", error)),
+        };
+
+        let db = Self {
+            connection,
+            filename: path.to_string_lossy().to_string(),
+        };
+        db.check_schema()?;
+        let version = db.schema_version()?;
+        if version < SCHEMA_VERSION {
+            bail!(
+                "{} has schema version {}, older than this version of Address Factory's {}. \
+                 Open it once with `serve` or `watch` to upgrade it.",
+                db.filename,
+                version,
+                SCHEMA_VERSION
+            )
+        }
+
+        Ok(db)
+    }
+
+    /// Whether the file is one of our databases. Opens it read-only, so unlike
+    /// `open` it never migrates anything.
+    pub fn is_ours(path: &Path) -> bool {
+        match Self::connect_read_only(path) {
+            Ok(connection) => Self {
+                connection,
+                filename: path.to_string_lossy().to_string(),
+            }
+            .check_schema()
+            .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn connect_read_only(path: &Path) -> rusqlite::Result<Connection> {
+        let mut wal = path.as_os_str().to_os_string();
+        wal.push("-wal");
+        // A read-only connection leaves -wal and -shm files behind, unless the
+        // file is opened as immutable. That's only safe with no WAL to read.
+        let (uri, flags) = if Path::new(&wal).exists() {
+            (
+                path.to_string_lossy().to_string(),
+                OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )
+        } else {
+            let escaped = path
+                .to_string_lossy()
+                .replace('%', "%25")
+                .replace('?', "%3f")
+                .replace('#', "%23");
+            (
+                format!("file:{}?immutable=1", escaped),
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+            )
+        };
+
+        Connection::open_with_flags(uri, flags)
+    }
+
+    /// Make sure this is one of our databases, and one this version knows how to read
+    fn check_schema(&self) -> Result<()> {
+        let tables: i32 = self.connection.query_row(
+            "SELECT count(*) FROM sqlite_master
+                WHERE type = 'table' AND name IN ('batches', 'entries')",
+            params![],
+            |row| row.get(0),
+        )?;
+        if tables != 2 {
+            bail!("{} isn't an Address Factory database", self.filename)
+        }
+
+        let version = self.schema_version()?;
+        if !(1..=SCHEMA_VERSION).contains(&version) {
+            bail!(
+                "{} has schema version {} but this version of Address Factory only knows up to {}",
+                self.filename,
+                version,
+                SCHEMA_VERSION
+            )
+        }
+
+        Ok(())
+    }
+
+    fn schema_version(&self) -> Result<i32> {
+        Ok(self
+            .connection
+            .query_row("PRAGMA user_version", params![], |row| row.get(0))?)
+    }
+
+    /// Bring an older database up to the current schema
+    fn migrate(&self) -> Result<()> {
+        let version = self.schema_version()?;
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+            self.connection.execute_batch(&format!(
+                "BEGIN;
+                 {}
+                 PRAGMA user_version = {};
+                 COMMIT;",
+                migration,
+                from + 2
+            ))?;
+        }
+
+        Ok(())
     }
 
-    pub fn insert(&self, entry: Entry) -> Result<()> {
+    /// Make sure a new batch belongs with the ones already in this database:
+    /// same wallet, same network, and no index handed out twice
+    pub fn check_batch_fits(&self, batch: &Batch) -> Result<()> {
+        let end = match batch.end_index() {
+            Some(end) => end,
+            None => bail!(
+                "Indexes {} and up don't fit in a batch of {}",
+                batch.start_index,
+                batch.count
+            ),
+        };
+        for existing in self.batches()? {
+            if existing.descriptor_checksum != batch.descriptor_checksum
+                || existing.network != batch.network
+            {
+                bail!(
+                    "{} holds addresses from a different wallet (descriptor checksum {} on {})",
+                    self.filename,
+                    existing.descriptor_checksum,
+                    existing.network
+                )
+            }
+
+            let existing_end = match existing.end_index() {
+                Some(existing_end) => existing_end,
+                None => bail!(
+                    "Batch {} in {} has an impossible index range",
+                    existing.id,
+                    self.filename
+                ),
+            };
+            if batch.start_index < existing_end && existing.start_index < end {
+                bail!(
+                    "{} already has indexes {}..{} in batch {}",
+                    self.filename,
+                    existing.start_index,
+                    existing_end,
+                    existing.id
+                )
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record a new batch and return its id for the entries that belong to it
+    pub fn new_batch(&self, batch: &Batch) -> Result<i64> {
         self.connection.execute(
-            "INSERT INTO entries (address, message) VALUES (?1, ?2)",
-            params![entry.address, entry.message],
+            "INSERT INTO batches (descriptor_checksum, network, start_index, count,
+                signing_key_fingerprint, created_at, tool_version, merkle_root, signed_root,
+                valid_until)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                batch.descriptor_checksum,
+                batch.network,
+                batch.start_index,
+                batch.count,
+                batch.signing_key_fingerprint,
+                batch.created_at,
+                batch.tool_version,
+                batch.merkle_root,
+                batch.signed_root,
+                batch.valid_until
+            ],
         )?;
 
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    /// Group the following writes into one transaction until `commit`.
+    /// Inserting a big batch one autocommit at a time is painfully slow.
+    pub fn begin(&self) -> Result<()> {
+        if self.connection.is_autocommit() {
+            self.connection.execute_batch("BEGIN")?;
+        }
         Ok(())
     }
 
-    pub fn print_entries(&self) -> Result<()> {
+    pub fn commit(&self) -> Result<()> {
+        if !self.connection.is_autocommit() {
+            self.connection.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+
+    /// Move everything from the write-ahead log into the database file itself,
+    /// so the file can be copied or hashed on its own
+    pub fn flush_wal(&self) -> Result<()> {
+        self.connection
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        Ok(())
+    }
+
+    /// Record how many entries of a batch have been stored so far, so a
+    /// batch cut short still says which indexes it holds
+    pub fn set_batch_count(&self, batch_id: i64, count: u32) -> Result<()> {
+        self.connection.execute(
+            "UPDATE batches SET count = ?1 WHERE id = ?2",
+            params![count, batch_id],
+        )?;
+        Ok(())
+    }
+
+    /// Insert an entry and return its id
+    pub fn insert(&self, entry: Entry) -> Result<i32> {
+        let mut stmt = self.connection.prepare_cached(
+            "INSERT INTO entries (batch_id, derivation_index, address, message, uri, merkle_proof,
+                statement)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        stmt.execute(params![
+            entry.batch_id,
+            entry.derivation_index,
+            entry.address,
+            entry.message,
+            entry.uri,
+            entry.merkle_proof,
+            entry.statement
+        ])?;
+
+        Ok(self.connection.last_insert_rowid() as i32)
+    }
+
+    pub fn insert_qr_code(&self, entry_id: i32, format: QrFormat, image: &[u8]) -> Result<()> {
         let mut stmt = self
             .connection
-            .prepare("SELECT id, address, message FROM entries")?;
-        let entry_itr = stmt.query_map(params![], |row| {
-            Ok(Entry {
-                id: row.get(0)?,
-                address: row.get(1)?,
-                message: row.get(2)?,
+            .prepare_cached("INSERT INTO qr_codes (entry_id, format, image) VALUES (?1, ?2, ?3)")?;
+        stmt.execute(params![entry_id, format.extension(), image])?;
+
+        Ok(())
+    }
+
+    pub fn qr_code(&self, entry_id: i32, format: QrFormat) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT image FROM qr_codes WHERE entry_id = ?1 AND format = ?2",
+                params![entry_id, format.extension()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// All batches in the order they were created
+    pub fn batches(&self) -> Result<Vec<Batch>> {
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {} FROM batches ORDER BY id",
+            BATCH_COLUMNS
+        ))?;
+        let batch_itr = stmt.query_map(params![], Batch::from_row)?;
+
+        let mut batches = vec![];
+        for batch in batch_itr {
+            batches.push(batch?);
+        }
+
+        Ok(batches)
+    }
+
+    /// All entries in the order they were inserted
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {} FROM entries ORDER BY id",
+            ENTRY_COLUMNS
+        ))?;
+        let entry_itr = stmt.query_map(params![], Entry::from_row)?;
+
+        let mut entries = vec![];
+        for entry in entry_itr {
+            entries.push(entry?);
+        }
+
+        Ok(entries)
+    }
+
+    pub fn batch(&self, id: i64) -> Result<Option<Batch>> {
+        Ok(self
+            .connection
+            .query_row(
+                &format!("SELECT {} FROM batches WHERE id = ?1", BATCH_COLUMNS),
+                params![id],
+                Batch::from_row,
+            )
+            .optional()?)
+    }
+
+    /// Addresses at the lowest and highest index of a batch
+    pub fn first_and_last_address(&self, batch_id: i64) -> Result<Option<(String, String)>> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT
+                    (SELECT address FROM entries WHERE batch_id = ?1
+                        ORDER BY derivation_index LIMIT 1),
+                    (SELECT address FROM entries WHERE batch_id = ?1
+                        ORDER BY derivation_index DESC LIMIT 1)
+                 WHERE EXISTS (SELECT 1 FROM entries WHERE batch_id = ?1)",
+                params![batch_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    pub fn entry(&self, id: i32) -> Result<Option<Entry>> {
+        Ok(self
+            .connection
+            .query_row(
+                &format!("SELECT {} FROM entries WHERE id = ?1", ENTRY_COLUMNS),
+                params![id],
+                Entry::from_row,
+            )
+            .optional()?)
+    }
+
+    /// Hand out the next address nobody has been given yet, or `None` if the pool is empty.
+    /// Addresses from expired batches are never handed out.
+    /// Runs in a write transaction so two requests can never get the same address.
+    pub fn next_unused(&mut self, label: Option<&str>) -> Result<Option<Entry>> {
+        let tx = self
+            .connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let id: Option<i32> = tx
+            .query_row(
+                "SELECT id FROM entries WHERE dispensed_at IS NULL AND paid_at IS NULL
+                    AND batch_id NOT IN (SELECT id FROM batches WHERE valid_until < ?1)
+                    ORDER BY batch_id, derivation_index LIMIT 1",
+                params![today()],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        tx.execute(
+            "UPDATE entries SET dispensed_at = ?1, label = ?2 WHERE id = ?3",
+            params![Local::now().to_rfc3339(), label, id],
+        )?;
+        tx.commit()?;
+
+        self.entry(id)
+    }
+
+    /// Record that a dispensed address has been paid
+    pub fn mark_paid(&self, id: i32) -> Result<()> {
+        let updated = self.connection.execute(
+            "UPDATE entries SET paid_at = ?1
+                WHERE id = ?2 AND dispensed_at IS NOT NULL AND paid_at IS NULL",
+            params![Local::now().to_rfc3339(), id],
+        )?;
+        if updated == 0 {
+            bail!("Entry {} hasn't been dispensed or is already paid", id)
+        }
+
+        Ok(())
+    }
+
+    /// Put a dispensed address that was never paid back in the pool, e.g. for an abandoned order
+    pub fn release(&self, id: i32) -> Result<()> {
+        let updated = self.connection.execute(
+            "UPDATE entries SET dispensed_at = NULL, label = NULL
+                WHERE id = ?1 AND dispensed_at IS NOT NULL AND paid_at IS NULL",
+            params![id],
+        )?;
+        if updated == 0 {
+            bail!("Entry {} hasn't been dispensed or is already paid", id)
+        }
+
+        Ok(())
+    }
+
+    /// Payments to an entry as of the last `sync_payments`
+    pub fn payments(&self, entry_id: i32) -> Result<Vec<Payment>> {
+        let mut stmt = self.connection.prepare_cached(
+            "SELECT txid, amount, height FROM payments WHERE entry_id = ?1 ORDER BY rowid",
+        )?;
+        let payment_itr = stmt.query_map(params![entry_id], |row| {
+            Ok(Payment {
+                txid: row.get(0)?,
+                amount: row.get::<_, i64>(1)? as u64,
+                height: row.get(2)?,
             })
         })?;
 
-        for entry in entry_itr {
-            println!("Found entry {:?}", entry.unwrap());
+        let mut payments = vec![];
+        for payment in payment_itr {
+            payments.push(payment?);
+        }
+
+        Ok(payments)
+    }
+
+    /// Replace an entry's payments with what the chain shows now and return the
+    /// ones that weren't recorded before. Transactions that dropped out of the
+    /// mempool are forgotten. The first payment marks the entry paid.
+    pub fn sync_payments(&mut self, entry_id: i32, seen: &[Payment]) -> Result<Vec<Payment>> {
+        let known = self.payments(entry_id)?;
+        let tx = self.connection.transaction()?;
+        let now = Local::now().to_rfc3339();
+
+        for payment in &known {
+            if !seen.iter().any(|seen| seen.txid == payment.txid) {
+                tx.execute(
+                    "DELETE FROM payments WHERE entry_id = ?1 AND txid = ?2",
+                    params![entry_id, payment.txid],
+                )?;
+            }
+        }
+
+        let mut new = vec![];
+        for payment in seen {
+            tx.execute(
+                "INSERT INTO payments (entry_id, txid, amount, height, first_seen_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT(entry_id, txid) DO UPDATE SET amount = ?3, height = ?4",
+                params![
+                    entry_id,
+                    payment.txid,
+                    payment.amount as i64,
+                    payment.height,
+                    now
+                ],
+            )?;
+            if !known.iter().any(|known| known.txid == payment.txid) {
+                new.push(payment.clone());
+            }
+        }
+
+        if !seen.is_empty() {
+            tx.execute(
+                "UPDATE entries SET paid_at = ?1 WHERE id = ?2 AND paid_at IS NULL",
+                params![now, entry_id],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(new)
+    }
+
+    /// Webhook events not delivered yet for payments to dispensed entries.
+    /// A payment counts as confirmed once it's `confirmations` deep below `tip`.
+    pub fn pending_notifications(
+        &self,
+        confirmations: u32,
+        tip: u32,
+    ) -> Result<Vec<(PaymentEvent, Entry, Payment)>> {
+        // Confirmed at or below this height means at least `confirmations` deep
+        let deep_enough = (tip + 1).saturating_sub(confirmations.max(1));
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {}, txid, amount, height, seen, confirmed FROM (
+                SELECT entries.*, txid, amount, height, payments.rowid AS position,
+                    seen_notified_at IS NULL AS seen,
+                    height IS NOT NULL AND height <= ?1
+                        AND confirmed_notified_at IS NULL AS confirmed
+                FROM payments JOIN entries ON entries.id = payments.entry_id
+                WHERE dispensed_at IS NOT NULL
+             ) WHERE seen OR confirmed
+             ORDER BY position",
+            ENTRY_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![deep_enough], |row| {
+            let payment = Payment {
+                txid: row.get(11)?,
+                amount: row.get::<_, i64>(12)? as u64,
+                height: row.get(13)?,
+            };
+            Ok((Entry::from_row(row)?, payment, row.get(14)?, row.get(15)?))
+        })?;
+
+        let mut pending = vec![];
+        for row in rows {
+            let (entry, payment, seen, confirmed) = row?;
+            if seen {
+                pending.push((PaymentEvent::Seen, entry.clone(), payment.clone()));
+            }
+            if confirmed {
+                pending.push((PaymentEvent::Confirmed, entry, payment));
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// Record that a webhook accepted an event, so it isn't sent again
+    pub fn mark_notified(&self, entry_id: i32, txid: &str, event: PaymentEvent) -> Result<()> {
+        let column = match event {
+            PaymentEvent::Seen => "seen_notified_at",
+            PaymentEvent::Confirmed => "confirmed_notified_at",
+        };
+        self.connection.execute(
+            &format!(
+                "UPDATE payments SET {} = ?1 WHERE entry_id = ?2 AND txid = ?3",
+                column
+            ),
+            params![Local::now().to_rfc3339(), entry_id, txid],
+        )?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        Ok(self.connection.query_row(
+            "SELECT count(*),
+                count(*) FILTER (WHERE dispensed_at IS NULL AND paid_at IS NULL AND NOT expired),
+                count(*) FILTER (WHERE dispensed_at IS NOT NULL AND paid_at IS NULL),
+                count(*) FILTER (WHERE paid_at IS NOT NULL),
+                count(*) FILTER (WHERE dispensed_at IS NULL AND paid_at IS NULL AND expired)
+                FROM (
+                    SELECT dispensed_at, paid_at, coalesce(valid_until < ?1, 0) AS expired
+                    FROM entries LEFT JOIN batches ON batches.id = entries.batch_id
+                )",
+            params![today()],
+            |row| {
+                Ok(Stats {
+                    total: row.get(0)?,
+                    unused: row.get(1)?,
+                    dispensed: row.get(2)?,
+                    paid: row.get(3)?,
+                    expired: row.get(4)?,
+                })
+            },
+        )?)
+    }
+
+    pub fn pool_level(&self, low_water: u32) -> Result<PoolLevel> {
+        let unused = self.stats()?.unused;
+        Ok(PoolLevel {
+            unused,
+            low_water,
+            low: unused < low_water || unused == 0,
+        })
+    }
+
+    pub fn print_entries(&self) -> Result<()> {
+        for entry in self.entries()? {
+            println!("Found entry {:?}", entry);
+        }
+
+        Ok(())
+    }
+}
+
+/// Today's date the way `valid_until` is written
+pub(crate) fn today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// A batch for tests that don't care about the details
+#[cfg(test)]
+pub(crate) fn test_batch() -> Batch {
+    Batch {
+        id: 0,
+        descriptor_checksum: "pjd3nkdg".to_string(),
+        network: "testnet".to_string(),
+        start_index: 0,
+        count: 2,
+        signing_key_fingerprint: "5D2A95DFDA07039A0BCF22D7F6E19D9562114850".to_string(),
+        created_at: "2021-03-20T12:00:00+00:00".to_string(),
+        tool_version: "0.1.0".to_string(),
+        merkle_root: None,
+        signed_root: None,
+        valid_until: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rusqlite::{params, Connection};
+
+    use super::{test_batch, Database, Entry, Payment};
+    use crate::Store;
+
+    #[test]
+    fn create_refuses_to_overwrite() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = Database::default_path(dir.path());
+
+        Database::create(&path)?;
+        assert!(Database::create(&path).is_err());
+
+        // The next default name steps around the file we just made
+        assert_ne!(Database::default_path(dir.path()), path);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_checks_schema() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        let other = dir.path().join("other.db");
+        Connection::open(&other)?.execute("CREATE TABLE stuff (id INTEGER)", params![])?;
+        assert!(Database::open(&other).is_err());
+        assert!(Database::open(&dir.path().join("missing.db")).is_err());
+
+        let path = dir.path().join("ours.db");
+        Database::create(&path)?;
+        Database::open(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_open_leaves_the_file_alone() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ours.db");
+        Database::create(&path)?.new_batch(&test_batch())?;
+
+        let db = Database::open_read_only(&path)?;
+        assert_eq!(db.batches()?.len(), 1);
+        assert!(db.new_batch(&test_batch()).is_err());
+        drop(db);
+
+        // An older database isn't migrated behind the verifier's back
+        Connection::open(&path)?.execute_batch("PRAGMA user_version = 1")?;
+        assert!(Database::open_read_only(&path).is_err());
+        let version: i32 =
+            Connection::open(&path)?
+                .query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        assert_eq!(version, 1);
+
+        assert!(Database::open_read_only(&dir.path().join("missing.db")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn addresses_are_unique() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Database::create(&dir.path().join("ours.db"))?;
+        let batch_id = db.new_batch(&test_batch())?;
+
+        db.insert(Entry::new(batch_id, 0, "tb1qaddress", "signed"))?;
+        assert!(db
+            .insert(Entry::new(batch_id, 1, "tb1qaddress", "signed"))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn appended_batches_must_fit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Database::create(&dir.path().join("ours.db"))?;
+        db.new_batch(&test_batch())?;
+
+        let mut next = test_batch();
+        next.start_index = 2;
+        db.check_batch_fits(&next)?;
+
+        let mut overlapping = test_batch();
+        overlapping.start_index = 1;
+        assert!(db.check_batch_fits(&overlapping).is_err());
+
+        let mut other_wallet = next.clone();
+        other_wallet.descriptor_checksum = "aaaaaaaa".to_string();
+        assert!(db.check_batch_fits(&other_wallet).is_err());
+
+        let mut too_long = next.clone();
+        too_long.count = u32::MAX;
+        assert!(db.check_batch_fits(&too_long).is_err());
+
+        // An edited count mustn't overflow when the next batch is checked against it
+        Connection::open(dir.path().join("ours.db"))?
+            .execute("UPDATE batches SET count = ?1", params![u32::MAX])?;
+        assert!(db.check_batch_fits(&next).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_batch_keeps_checkpoints() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ours.db");
+
+        {
+            let mut db = Database::create(&path)?;
+            let batch_id = db.create_batch(&test_batch())?;
+            db.insert_entry(Entry::new(batch_id, 0, "tb1qfirst", "signed first"))?;
+            db.checkpoint(batch_id, 1)?;
+            // Dropped before the second checkpoint, like a crash
+            db.insert_entry(Entry::new(batch_id, 1, "tb1qsecond", "signed second"))?;
         }
 
+        let db = Database::open(&path)?;
+        assert_eq!(db.entries()?.len(), 1);
+        assert_eq!(db.batches()?[0].count, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dispensing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = Database::create(&dir.path().join("ours.db"))?;
+        let batch_id = db.new_batch(&test_batch())?;
+        db.insert(Entry::new(batch_id, 0, "tb1qfirst", "signed"))?;
+        db.insert(Entry::new(batch_id, 1, "tb1qsecond", "signed"))?;
+
+        let first = db.next_unused(Some("order 1"))?.unwrap();
+        assert_eq!(first.address, "tb1qfirst");
+        assert_eq!(first.label.as_deref(), Some("order 1"));
+        assert!(first.dispensed_at.is_some());
+
+        let second = db.next_unused(None)?.unwrap();
+        assert_eq!(second.address, "tb1qsecond");
+        assert!(db.next_unused(None)?.is_none());
+
+        db.mark_paid(first.id)?;
+        assert!(db.release(first.id).is_err());
+        db.release(second.id)?;
+
+        let stats = db.stats()?;
+        assert_eq!(
+            (stats.total, stats.unused, stats.dispensed, stats.paid),
+            (2, 1, 0, 1)
+        );
+        assert!(!db.pool_level(1)?.low);
+        assert!(db.pool_level(2)?.low);
+        assert_eq!(db.next_unused(None)?.unwrap().address, "tb1qsecond");
+        // Running out is low whatever the mark
+        assert!(db.pool_level(0)?.low);
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_expired_batches() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = Database::create(&dir.path().join("ours.db"))?;
+        let mut expired = test_batch();
+        expired.valid_until = Some("2021-03-20".to_string());
+        let mut current = test_batch();
+        current.start_index = 2;
+        current.valid_until = Some("9999-12-31".to_string());
+        let expired_id = db.new_batch(&expired)?;
+        let current_id = db.new_batch(&current)?;
+        db.insert(Entry::new(expired_id, 0, "tb1qold", "signed"))?;
+        db.insert(Entry::new(current_id, 2, "tb1qnew", "signed"))?;
+
+        assert!(db.batches()?[0].is_expired());
+        assert!(!db.batches()?[1].is_expired());
+        let stats = db.stats()?;
+        assert_eq!((stats.unused, stats.expired), (1, 1));
+        assert_eq!(db.next_unused(None)?.unwrap().address, "tb1qnew");
+        assert!(db.next_unused(None)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn never_dispenses_paid_addresses() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = Database::create(&dir.path().join("ours.db"))?;
+        let batch_id = db.new_batch(&test_batch())?;
+        let paid = db.insert(Entry::new(batch_id, 0, "tb1qfirst", "signed"))?;
+        db.insert(Entry::new(batch_id, 1, "tb1qsecond", "signed"))?;
+
+        // Somebody paid an address before it was handed out, e.g. from an old page
+        let payment = Payment {
+            txid: "aa".to_string(),
+            amount: 1000,
+            height: None,
+        };
+        db.sync_payments(paid, &[payment])?;
+
+        let stats = db.stats()?;
+        assert_eq!(
+            (stats.total, stats.unused, stats.dispensed, stats.paid),
+            (2, 1, 0, 1)
+        );
+        assert_eq!(db.next_unused(None)?.unwrap().address, "tb1qsecond");
+        assert!(db.next_unused(None)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn syncs_payments() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = Database::create(&dir.path().join("ours.db"))?;
+        let batch_id = db.new_batch(&test_batch())?;
+        let id = db.insert(Entry::new(batch_id, 0, "tb1qfirst", "signed"))?;
+
+        let mempool = Payment {
+            txid: "aa".to_string(),
+            amount: 1000,
+            height: None,
+        };
+        assert_eq!(
+            db.sync_payments(id, std::slice::from_ref(&mempool))?,
+            vec![mempool]
+        );
+        assert!(db.entry(id)?.unwrap().paid_at.is_some());
+
+        // It confirms, and a second payment replaces nothing
+        let confirmed = Payment {
+            txid: "aa".to_string(),
+            amount: 1000,
+            height: Some(100),
+        };
+        let second = Payment {
+            txid: "bb".to_string(),
+            amount: 2000,
+            height: None,
+        };
+        assert_eq!(
+            db.sync_payments(id, &[confirmed.clone(), second.clone()])?,
+            vec![second]
+        );
+        assert_eq!(db.payments(id)?[0], confirmed);
+
+        // The second one was double spent away
+        db.sync_payments(id, std::slice::from_ref(&confirmed))?;
+        assert_eq!(db.payments(id)?, vec![confirmed]);
+
         Ok(())
     }
 }
@@ -0,0 +1,121 @@
+// Derive addresses straight from a descriptor. Going through a bdk Wallet
+// means a wallet and database per lookup and walking every index in order,
+// which gets slow for big batches or far-off indexes.
+
+use anyhow::{bail, Result};
+use bdk::{
+    bitcoin::{secp256k1::Secp256k1, secp256k1::VerifyOnly, Address, Network, PublicKey},
+    miniscript::{DescriptorPublicKey, DescriptorTrait, TranslatePk2},
+};
+use rayon::prelude::*;
+
+use crate::Desc;
+
+pub struct Deriver {
+    /// The descriptor with every xpub already derived down to the step before
+    /// the wildcard, e.g. `xpub/0`, so each address is one more derivation
+    template: Desc,
+    network: Network,
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl Deriver {
+    pub fn new(descriptor: &Desc, network: Network) -> Result<Self> {
+        let secp = Secp256k1::verification_only();
+
+        let template = descriptor.translate_pk2(|key| match key {
+            DescriptorPublicKey::XPub(xpub) => {
+                let mut xpub = xpub.clone();
+                xpub.xkey = xpub.xkey.derive_pub(&secp, &xpub.derivation_path)?;
+                xpub.derivation_path = vec![].into();
+                Ok(DescriptorPublicKey::XPub(xpub))
+            }
+            single => Ok(single.clone()),
+        });
+        let template = match template {
+            Ok(template) => template,
+            Err(bdk::bitcoin::util::bip32::Error::CannotDeriveFromHardenedKey) => {
+                bail!("Can't derive addresses through hardened steps without the private key")
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Self {
+            template,
+            network,
+            secp,
+        })
+    }
+
+    pub fn address(&self, index: u32) -> Result<Address> {
+        let descriptor = self.template.translate_pk2(|key| -> Result<PublicKey> {
+            Ok(key.clone().derive(index).derive_public_key(&self.secp)?)
+        })?;
+
+        Ok(descriptor.address(self.network)?)
+    }
+
+    /// `count` addresses from `start` on, spread across all cores
+    pub fn addresses(&self, start: u32, count: u32) -> Result<Vec<Address>> {
+        (start..start + count)
+            .into_par_iter()
+            .map(|index| self.address(index))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bdk::{
+        bitcoin::Network, database::MemoryDatabase, descriptor::ExtendedDescriptor,
+        wallet::AddressIndex, Wallet,
+    };
+
+    use super::Deriver;
+
+    #[test]
+    fn matches_bdk() -> Result<()> {
+        let xpub = "tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET";
+        let other = "tpubDCiHGUNYdRRGoSH22j8YnruUKgguCK1CC2NFQUf9PApeZh8ewAJJWGMUrhggDNK73iCTanWXv1RN5FYemUH8UrVUBjqDb8WF2VoKmDh9UTo";
+        let descriptors = [
+            format!("wpkh({}/0/*)", xpub),
+            format!("pkh([78cf94e5/84'/1'/123']{}/1/*)", xpub),
+            format!("sh(wpkh({}/*))", xpub),
+            format!("wsh(sortedmulti(1,{}/0/*,{}/0/*))", xpub, other),
+        ];
+
+        for descriptor in &descriptors {
+            let descriptor: ExtendedDescriptor = descriptor.parse()?;
+            let deriver = Deriver::new(&descriptor, Network::Testnet)?;
+            let wallet = Wallet::new_offline(
+                descriptor,
+                None,
+                Network::Testnet,
+                MemoryDatabase::default(),
+            )?;
+
+            let addresses = deriver.addresses(0, 20)?;
+            for (index, address) in addresses.iter().enumerate() {
+                assert_eq!(
+                    *address,
+                    wallet.get_address(AddressIndex::Peek(index as u32))?
+                );
+            }
+            assert_eq!(
+                deriver.address(5000)?,
+                wallet.get_address(AddressIndex::Peek(5000))?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_hardened_steps() -> Result<()> {
+        let descriptor: ExtendedDescriptor = "wpkh(tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET/0h/*)".parse()?;
+        assert!(Deriver::new(&descriptor, Network::Testnet).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use serde_json::json;
+
+use crate::{Database, Entry};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    /// One address per line
+    Text,
+    /// BIP-329 wallet labels, one `addr` record per line
+    Bip329,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "txt" | "text" => Ok(ExportFormat::Text),
+            "bip329" => Ok(ExportFormat::Bip329),
+            _ => bail!(
+                "Unknown export format {}, expected csv, jsonl, txt or bip329",
+                s
+            ),
+        }
+    }
+}
+
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Only export this batch
+    pub batch: Option<i64>,
+    /// Also write each signed message to `<address>.asc` in a folder next to the export
+    pub signatures: bool,
+    /// Start each BIP-329 label with this instead of the text the address was signed with.
+    /// Labels end with whatever the address was dispensed for, e.g. the order id.
+    pub message: Option<String>,
+    /// The factory's message, for addresses stored before their signed text was kept
+    pub factory_message: Option<String>,
+}
+
+/// Write the database's entries to `out` and return how many were written
+pub fn export(db: &Database, out: &Path, options: &ExportOptions) -> Result<usize> {
+    let entries: Vec<Entry> = db
+        .entries()?
+        .into_iter()
+        .filter(|entry| options.batch.is_none_or(|batch| entry.batch_id == batch))
+        .collect();
+
+    let mut writer = BufWriter::new(File::create(out)?);
+    match options.format {
+        ExportFormat::Csv => write_csv(&mut writer, &entries)?,
+        ExportFormat::Jsonl => write_jsonl(&mut writer, &entries)?,
+        ExportFormat::Text => write_text(&mut writer, &entries)?,
+        ExportFormat::Bip329 => write_bip329(&mut writer, &entries, options)?,
+    }
+    writer.flush()?;
+
+    if options.signatures {
+        let dir = signatures_dir(out);
+        fs::create_dir_all(&dir)?;
+        for entry in &entries {
+            fs::write(dir.join(format!("{}.asc", entry.address)), &entry.message)?;
+        }
+    }
+
+    Ok(entries.len())
+}
+
+/// `addresses.txt` gets its signatures in `addresses_signatures/`
+pub fn signatures_dir(out: &Path) -> PathBuf {
+    let stem = out.file_stem().unwrap_or_default().to_string_lossy();
+    out.with_file_name(format!("{}_signatures", stem))
+}
+
+fn write_csv(writer: &mut impl Write, entries: &[Entry]) -> Result<()> {
+    writeln!(
+        writer,
+        "id,batch_id,derivation_index,address,uri,signed_message,dispensed_at,label,paid_at"
+    )?;
+
+    for entry in entries {
+        let fields = [
+            entry.id.to_string(),
+            entry.batch_id.to_string(),
+            entry.derivation_index.to_string(),
+            entry.address.clone(),
+            entry.uri.clone().unwrap_or_default(),
+            entry.message.clone(),
+            entry.dispensed_at.clone().unwrap_or_default(),
+            entry.label.clone().unwrap_or_default(),
+            entry.paid_at.clone().unwrap_or_default(),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        writeln!(writer, "{}", row.join(","))?;
+    }
+
+    Ok(())
+}
+
+/// Quote a field if it needs it (RFC 4180). Signed messages always do, they're multi-line.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_jsonl(writer: &mut impl Write, entries: &[Entry]) -> Result<()> {
+    for entry in entries {
+        let line = json!({
+            "id": entry.id,
+            "batch_id": entry.batch_id,
+            "derivation_index": entry.derivation_index,
+            "address": entry.address,
+            "uri": entry.uri,
+            "signed_message": entry.message,
+            "statement": entry.statement,
+            "dispensed_at": entry.dispensed_at,
+            "label": entry.label,
+            "paid_at": entry.paid_at,
+            "merkle_proof": entry.merkle_proof,
+        });
+        writeln!(writer, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+fn write_text(writer: &mut impl Write, entries: &[Entry]) -> Result<()> {
+    for entry in entries {
+        writeln!(writer, "{}", entry.address)?;
+    }
+
+    Ok(())
+}
+
+/// Label each address with where it came from, so wallets can show it next to payments
+fn write_bip329(writer: &mut impl Write, entries: &[Entry], options: &ExportOptions) -> Result<()> {
+    for entry in entries {
+        let line = json!({
+            "type": "addr",
+            "ref": entry.address,
+            "label": bip329_label(entry, options),
+        });
+        writeln!(writer, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+fn bip329_label(entry: &Entry, options: &ExportOptions) -> String {
+    // The factory's message is a template, so use what was actually signed for this address
+    let message = match (&options.message, &entry.statement) {
+        (Some(message), _) => message.clone(),
+        (None, Some(statement)) => statement.lines().collect::<Vec<_>>().join(" "),
+        (None, None) => options.factory_message.clone().unwrap_or_default(),
+    };
+    let mut label = format!("batch {} index {}", entry.batch_id, entry.derivation_index);
+    if !message.is_empty() {
+        label = format!("{} ({})", message, label);
+    }
+    if let Some(order) = &entry.label {
+        label = format!("{}, {}", label, order);
+    }
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use anyhow::Result;
+
+    use super::{csv_field, export, signatures_dir, ExportFormat, ExportOptions};
+    use crate::{database::test_batch, Database, Entry};
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("tb1qaddress"), "tb1qaddress");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\"\n"), "\"say \"\"hi\"\"\n\"");
+    }
+
+    #[test]
+    fn exports_a_batch() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = Database::create(&dir.path().join("ours.db"))?;
+        let mut batch = test_batch();
+        batch.count = 1;
+        let first = db.new_batch(&batch)?;
+        db.insert(Entry::new(first, 0, "tb1qfirst", "signed first"))?;
+        batch.start_index = 1;
+        let second = db.new_batch(&batch)?;
+        db.insert(Entry::new(second, 1, "tb1qsecond", "signed second"))?;
+
+        let out = dir.path().join("addresses.txt");
+        let options = ExportOptions {
+            format: ExportFormat::Text,
+            batch: Some(second),
+            signatures: true,
+            message: None,
+            factory_message: None,
+        };
+        assert_eq!(export(&db, &out, &options)?, 1);
+        assert_eq!(fs::read_to_string(&out)?, "tb1qsecond\n");
+        assert_eq!(
+            fs::read_to_string(signatures_dir(&out).join("tb1qsecond.asc"))?,
+            "signed second"
+        );
+
+        let out = dir.path().join("addresses.jsonl");
+        let options = ExportOptions {
+            format: ExportFormat::Jsonl,
+            batch: None,
+            signatures: false,
+            message: None,
+            factory_message: None,
+        };
+        assert_eq!(export(&db, &out, &options)?, 2);
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&out)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines[1]["address"], "tb1qsecond");
+        assert_eq!(lines[1]["derivation_index"], 1);
+
+        db.next_unused(Some("order 42"))?;
+        let out = dir.path().join("labels.jsonl");
+        let options = ExportOptions {
+            format: ExportFormat::Bip329,
+            batch: None,
+            signatures: false,
+            message: None,
+            factory_message: Some("Thanks for the donation".to_string()),
+        };
+        assert_eq!(export(&db, &out, &options)?, 2);
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&out)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines[0]["type"], "addr");
+        assert_eq!(lines[0]["ref"], "tb1qfirst");
+        assert_eq!(
+            lines[0]["label"],
+            "Thanks for the donation (batch 1 index 0), order 42"
+        );
+        assert_eq!(
+            lines[1]["label"],
+            "Thanks for the donation (batch 2 index 1)"
+        );
+
+        // Addresses signed from a template are labelled with their own text
+        let mut entry = Entry::new(second, 2, "tb1qthird", "signed third");
+        entry.statement =
+            Some("Donation #2 to Alice: tb1qthird\nValid until: 2099-12-31".to_string());
+        db.insert(entry)?;
+        assert_eq!(export(&db, &out, &options)?, 3);
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&out)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            lines[2]["label"],
+            "Donation #2 to Alice: tb1qthird Valid until: 2099-12-31 (batch 2 index 2)"
+        );
+        let options = ExportOptions {
+            message: Some("Donation".to_string()),
+            ..options
+        };
+        export(&db, &out, &options)?;
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&out)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines[2]["label"], "Donation (batch 2 index 2)");
+
+        assert_eq!(
+            signatures_dir(Path::new("out/addresses.csv")),
+            Path::new("out/addresses_signatures")
+        );
+
+        Ok(())
+    }
+}
@@ -1,22 +1,59 @@
 use std::process::Command;
 use std::{
-    fmt,
-    fs::{self, File},
-    path::PathBuf,
+    fmt, fs,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bdk::{
     bitcoin::{self, secp256k1::Secp256k1, Address},
-    database::MemoryDatabase,
     descriptor::ExtendedDescriptor,
-    wallet::AddressIndex,
-    Wallet,
 };
+use chrono::{Local, NaiveDate};
 use indicatif::{ProgressBar, ProgressStyle};
 use serde::{Deserialize, Serialize};
 
-use crate::{gpg_clearsign, util, util::Desc, Database, Entry};
+use crate::{
+    bip21::{payment_uri, PaymentRequest},
+    derive::Deriver,
+    gpg_clearsign_text, gpg_decrypt, gpg_encrypt, gpg_signing_key,
+    history::BatchRecord,
+    is_gpg_message,
+    manifest::sha256_file,
+    merkle::{root_statement, MerkleTree},
+    qr::{qr_code, QrFormat},
+    template::{self, Values},
+    util,
+    util::Desc,
+    Batch, Entry, Store,
+};
+
+/// How many entries to store between commits. The factory's position is saved
+/// at each one, so an interrupted run only redoes the last few addresses.
+const CHECKPOINT_INTERVAL: u32 = 1000;
+
+/// Extras to generate alongside each address
+#[derive(Debug, Default)]
+pub struct UriOptions {
+    /// Used to build a BIP21 URI per address. The label defaults to the factory's message.
+    pub request: PaymentRequest,
+    /// QR codes of the URI to render
+    pub qr_formats: Vec<QrFormat>,
+    /// Write QR codes here as `<address>.<format>` instead of into the database
+    pub qr_dir: Option<PathBuf>,
+}
+
+/// How to generate a batch
+#[derive(Debug, Default)]
+pub struct GenerateOptions {
+    pub uris: Option<UriOptions>,
+    /// Sign one Merkle root over the batch instead of every address
+    pub merkle: bool,
+    /// Put the full descriptor in the signed manifest, not just its checksum
+    pub manifest_descriptor: bool,
+    /// Last day the addresses should be handed out, signed into every statement
+    pub valid_until: Option<NaiveDate>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Factory {
@@ -27,6 +64,13 @@ pub struct Factory {
     pub message: String,
     pub network: bitcoin::Network,
     pub config_dir: PathBuf,
+    /// Every batch written so far, oldest first
+    #[serde(default)]
+    pub batches: Vec<BatchRecord>,
+    /// Save address-factory.json encrypted to the signing key, so the file
+    /// alone doesn't give away the xpub. Set when the file was loaded encrypted.
+    #[serde(skip)]
+    pub encrypted: bool,
 }
 
 impl fmt::Display for Factory {
@@ -63,30 +107,52 @@ impl Factory {
             message,
             network,
             config_dir,
+            batches: vec![],
+            encrypted: false,
         })
     }
 
-    /// Load address-factory.json
+    /// Load address-factory.json, decrypting it first if it was saved encrypted
     pub fn from_path(path: PathBuf) -> Result<Self> {
-        let state_json = fs::read_to_string(path)?;
-        let gen_state = serde_json::from_str(&state_json)?;
+        let mut state_json = fs::read_to_string(path)?;
+        let encrypted = is_gpg_message(&state_json);
+        if encrypted {
+            state_json = gpg_decrypt(&state_json)?;
+        }
+        let mut gen_state: Self = serde_json::from_str(&state_json)?;
+        gen_state.encrypted = encrypted;
 
         Ok(gen_state)
     }
 
-    /// Increment the next_index
-    pub fn finish(&mut self, peek_next_address: Address) {
-        let old_next_index = self.next_index;
-        self.next_index = old_next_index + self.number_to_generate;
-        self.next_address = peek_next_address;
+    /// Move past the addresses stored so far and save, so the next run picks up from here
+    pub fn checkpoint(&mut self, next_index: u32, next_address: Address) -> Result<()> {
+        self.next_index = next_index;
+        self.next_address = next_address;
+        self.save()
     }
 
-    /// Save the struct as .json
+    /// Bring the current batch's record up to the entries stored so far.
+    /// Doesn't save, the checkpoint after it does.
+    fn record_stored(&mut self, stored: u32) -> Result<()> {
+        let record = match self.batches.last_mut() {
+            Some(record) => record,
+            None => bail!("No batch has been recorded yet"),
+        };
+        record.count = stored;
+        record.sha256 = sha256_file(&record.file)?.to_string();
+        Ok(())
+    }
+
+    /// Save the struct as .json, encrypted to the signing key if `encrypted` is set
     pub fn save(&self) -> Result<()> {
         fs::create_dir_all(self.config_dir.clone())?;
         let config_file_path = self.config_dir.join("address-factory.json");
-        let f = File::create(config_file_path)?;
-        serde_json::to_writer_pretty(f, self)?;
+        let mut state_json = serde_json::to_string_pretty(self)?;
+        if self.encrypted {
+            state_json = gpg_encrypt(&state_json, &gpg_signing_key()?)?;
+        }
+        fs::write(config_file_path, state_json)?;
         Ok(())
     }
 
@@ -100,21 +166,22 @@ impl Factory {
         )
     }
 
-    pub fn generate_addresses(&mut self) -> Result<()> {
-        // This only peeks at the next address
-        self.check_next_address()?;
-        let desc = self.descriptor.clone();
-
-        let wallet = Wallet::new_offline(desc, None, self.network, MemoryDatabase::default())?;
-
-        if self.next_index > 0 {
-            println!("Skipping addresses...");
-            // This mutates the wallet to be the index right before we want to begin
-            wallet.get_address(AddressIndex::Reset(self.next_index - 1))?;
+    /// Derive, sign and store the next batch of addresses in `store`,
+    /// optionally with payment URIs and QR codes
+    pub fn generate_addresses(
+        &mut self,
+        store: &mut dyn Store,
+        options: &GenerateOptions,
+    ) -> Result<()> {
+        let uris = options.uris.as_ref();
+        template::check(&self.message, uris.is_some(), options.valid_until.is_some())?;
+        if let Some(valid_until) = options.valid_until {
+            if valid_until < Local::today().naive_local() {
+                bail!("--valid-until {} has already passed", valid_until)
+            }
         }
-
-        // Create a new SQLite db file and connect to it
-        let db = Database::new()?;
+        self.check_next_address()?;
+        let deriver = Deriver::new(&self.descriptor, self.network)?;
 
         // Don't want people staring at a blank prompt for minutes
         let pb = ProgressBar::new(self.number_to_generate as u64);
@@ -128,19 +195,24 @@ impl Factory {
 
         println!("Generating addresses...");
 
-        for _i in 0..self.number_to_generate {
-            // Each time we ask for a New address BDK advances the index by 1
-            let address = wallet.get_address(AddressIndex::New)?;
-            addresses.push(address);
-            pb.inc(1);
+        // Derive in parallel a chunk at a time so the progress bar still moves
+        let end = self.next_index + self.number_to_generate;
+        for start in (self.next_index..end).step_by(CHECKPOINT_INTERVAL as usize) {
+            let count = CHECKPOINT_INTERVAL.min(end - start);
+            addresses.extend(deriver.addresses(start, count)?);
+            pb.inc(count as u64);
         }
 
         pb.finish();
 
-        let message_text = &self.message;
+        let message_text = self.message.clone();
 
         // Would be nice to do this in parallel with rayon but gpg doesn't like that
-        println!("PGP signing addresses...");
+        if options.merkle {
+            println!("Storing addresses under a signed Merkle root...");
+        } else {
+            println!("PGP signing addresses...");
+        }
         let pb = ProgressBar::new(self.number_to_generate as u64);
         pb.set_style(
             ProgressStyle::default_bar()
@@ -161,22 +233,153 @@ impl Factory {
             }
         }
 
-        for address in addresses {
+        let fingerprint = gpg_signing_key()?;
+        let mut batch = Batch::new(
+            &self.descriptor,
+            self.network,
+            self.next_index,
+            self.number_to_generate,
+            &fingerprint,
+        );
+        batch.valid_until = options.valid_until.map(|date| date.to_string());
+
+        let tree = if options.merkle {
+            let address_strings: Vec<String> = addresses.iter().map(Address::to_string).collect();
+            let tree = MerkleTree::new(
+                (self.next_index..).zip(address_strings.iter().map(String::as_str)),
+            )?;
+            let statement = root_statement(&message_text, &batch, &tree.root());
+            batch.merkle_root = Some(tree.root().to_string());
+            batch.signed_root = Some(gpg_clearsign_text(&statement)?);
+            Some(tree)
+        } else {
+            None
+        };
+        let batch_id = store.create_batch(&batch)?;
+        let batch_number = self
+            .batches
+            .iter()
+            .filter(|record| record.count > 0)
+            .count()
+            + 1;
+
+        // Recorded now and kept up to date at each checkpoint, so a run that's cut
+        // short still leaves its partial batch in the history
+        let start_index = self.next_index;
+        let file = fs::canonicalize(store.location())?;
+        self.batches.push(BatchRecord {
+            sha256: sha256_file(&file)?.to_string(),
+            file,
+            start_index,
+            count: 0,
+            created_at: batch.created_at.clone(),
+            message: message_text.clone(),
+            signing_key_fingerprint: fingerprint,
+            valid_until: batch.valid_until.clone(),
+        });
+        self.save()?;
+
+        let network = self.network.to_string();
+        let date = batch.created_at.get(..10).unwrap_or_default().to_string();
+        if let Some(qr_dir) = uris.and_then(|uris| uris.qr_dir.as_ref()) {
+            fs::create_dir_all(qr_dir)?;
+        }
+
+        for (i, address) in addresses.iter().enumerate() {
+            let index = start_index + i as u32;
             let address = address.to_string();
-            let signed_message = gpg_clearsign(&address.to_string(), message_text)?;
+            let mut values = Values {
+                address: &address,
+                index,
+                date: &date,
+                batch: batch_number,
+                network: &network,
+                uri: None,
+                valid_until: batch.valid_until.as_deref(),
+            };
+
+            // The URI's label defaults to the message, so it can't include {uri}
+            let uri = uris.map(|uris| {
+                let mut request = uris.request.clone();
+                if request.label.is_none() {
+                    request.label = Some(template::render(&message_text, &values));
+                }
+                payment_uri(&address, &request)
+            });
+            values.uri = uri.as_deref();
+            let statement = template::statement(&message_text, &values);
+
+            let mut entry = match &tree {
+                Some(tree) => {
+                    let mut entry = Entry::new(batch_id, index, &address, "");
+                    entry.merkle_proof = Some(tree.proof(i).to_string());
+                    entry
+                }
+                None => {
+                    let signed_message = gpg_clearsign_text(&statement)?;
+                    Entry::new(batch_id, index, &address, &signed_message)
+                }
+            };
             pb.inc(1);
-            let entry = Entry::new(&address, &signed_message);
-            db.insert(entry)?;
+            entry.uri = uri.clone();
+            // Only the root is signed in a Merkle batch, so there's no statement to keep
+            if tree.is_none() {
+                entry.statement = Some(statement);
+            }
+            let entry_id = store.insert_entry(entry)?;
+
+            if let (Some(uri), Some(uris)) = (uri, uris) {
+                for format in &uris.qr_formats {
+                    let image = qr_code(&uri, *format)?;
+                    match &uris.qr_dir {
+                        Some(dir) => fs::write(
+                            dir.join(format!("{}.{}", address, format.extension())),
+                            image,
+                        )?,
+                        None => store.insert_qr_code(entry_id, *format, &image)?,
+                    }
+                }
+            }
+
+            let stored = i as u32 + 1;
+            if stored.is_multiple_of(CHECKPOINT_INTERVAL) && stored < self.number_to_generate {
+                store.checkpoint(batch_id, stored)?;
+                self.record_stored(stored)?;
+                self.checkpoint(index + 1, addresses[i + 1].clone())?;
+            }
         }
 
         pb.finish();
+        store.checkpoint(batch_id, self.number_to_generate)?;
+        store.finish()?;
+        self.record_stored(self.number_to_generate)?;
 
-        self.finish(wallet.get_address(AddressIndex::New)?);
-        self.save()?;
+        let next_index = start_index + self.number_to_generate;
+        self.checkpoint(next_index, deriver.address(next_index)?)?;
+
+        let mut manifest = store.manifest()?;
+        if options.manifest_descriptor {
+            manifest = manifest.with_descriptor(&self.descriptor);
+        }
+        let manifest_path = manifest.write(Path::new(&store.location()))?;
+
+        if options.merkle {
+            println!(
+                "Wrote {} addresses and their signed Merkle root to {}",
+                self.number_to_generate,
+                store.location()
+            );
+        } else {
+            println!(
+                "Wrote {} addresses and PGP signed messages to {}",
+                self.number_to_generate,
+                store.location()
+            );
+        }
 
         println!(
-            "Wrote {} addresses and PGP signed messages to {}",
-            self.number_to_generate, db.filename
+            "Signed a manifest of it to {}",
+            manifest_path.to_string_lossy()
         );
 
         println!(
@@ -190,3 +393,217 @@ impl Factory {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, process::Command};
+
+    use anyhow::Result;
+    use bdk::bitcoin::{Amount, Network};
+    use rusqlite::Connection;
+
+    use super::{Factory, GenerateOptions, UriOptions};
+    use crate::{
+        bip21::PaymentRequest,
+        gpg::test_gnupg_home,
+        gpg_signing_key, history, is_gpg_message,
+        manifest::sha256_file,
+        qr::{qr_code, QrFormat},
+        verify_database, verify_signatures, Database, Keyring, Problem,
+    };
+
+    fn test_factory(config_dir: &Path, number_to_generate: u32) -> Result<Factory> {
+        Factory::new(
+            "wpkh(tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET/0/*)".to_string(),
+            Network::Testnet,
+            5,
+            number_to_generate,
+            "Donate to Alice".to_string(),
+            config_dir.to_path_buf(),
+        )
+    }
+
+    #[test]
+    fn saves_and_loads_encrypted() -> Result<()> {
+        test_gnupg_home();
+        let dir = tempfile::tempdir()?;
+        let mut factory = test_factory(dir.path(), 100)?;
+        factory.encrypted = true;
+        factory.save()?;
+
+        let path = dir.path().join("address-factory.json");
+        let saved = fs::read_to_string(&path)?;
+        assert!(is_gpg_message(&saved));
+        assert!(!saved.contains("Donate to Alice"));
+
+        let loaded = Factory::from_path(path)?;
+        assert!(loaded.encrypted);
+        assert_eq!(loaded.message, "Donate to Alice");
+        assert_eq!(loaded.next_index, 5);
+        assert_eq!(loaded.next_address, factory.next_address);
+        assert_eq!(loaded.descriptor, factory.descriptor);
+
+        // It was encrypted to the key that signs
+        let listing = Command::new("gpg")
+            .args(["--list-secret-keys", "--with-colons"])
+            .output()?;
+        let listing = String::from_utf8(listing.stdout)?;
+        let fingerprint = gpg_signing_key()?;
+        assert_eq!(fingerprint.len(), 40);
+        assert!(listing.contains(&format!("fpr:::::::::{}:", fingerprint)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn generated_batches_match_the_history() -> Result<()> {
+        test_gnupg_home();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ours.db");
+        let mut factory = test_factory(dir.path(), 3)?;
+
+        // Twice into the same file, the second time the way --append does
+        let mut db = Database::create(&path)?;
+        factory.generate_addresses(&mut db, &GenerateOptions::default())?;
+        drop(db);
+        let mut db = Database::open(&path)?;
+        factory.generate_addresses(&mut db, &GenerateOptions::default())?;
+        drop(db);
+
+        let factory = Factory::from_path(dir.path().join("address-factory.json"))?;
+        let starts: Vec<(u32, u32)> = factory
+            .batches
+            .iter()
+            .map(|record| (record.start_index, record.count))
+            .collect();
+        assert_eq!(starts, vec![(5, 3), (8, 3)]);
+        assert_eq!(factory.next_index, 11);
+        assert_eq!(factory.batches[1].sha256, sha256_file(&path)?.to_string());
+        assert_eq!(history::problems(&factory.batches)?, vec![]);
+
+        let db = Database::open(&path)?;
+        assert_eq!(db.entries()?.len(), 6);
+        assert_eq!(
+            db.entries()?[0].statement.as_deref(),
+            Some(format!("Donate to Alice {}", db.entries()?[0].address).as_str())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn generates_payment_uris_and_qr_codes() -> Result<()> {
+        let home = test_gnupg_home();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ours.db");
+        let mut factory = test_factory(dir.path(), 2)?;
+        factory.message = "Donation #{index} to Alice".to_string();
+
+        let mut db = Database::create(&path)?;
+        let options = GenerateOptions {
+            uris: Some(UriOptions {
+                request: PaymentRequest {
+                    amount: Some(Amount::from_sat(10_000)),
+                    ..PaymentRequest::default()
+                },
+                qr_formats: vec![QrFormat::Png, QrFormat::Svg],
+                qr_dir: None,
+            }),
+            ..GenerateOptions::default()
+        };
+        factory.generate_addresses(&mut db, &options)?;
+
+        let entry = &db.entries()?[0];
+        assert_eq!(
+            entry.uri.as_deref(),
+            Some(
+                format!(
+                    "bitcoin:{}?amount=0.00010000&label=Donation%20%235%20to%20Alice",
+                    entry.address
+                )
+                .as_str()
+            )
+        );
+        let uri = entry.uri.as_deref().unwrap();
+        for format in [QrFormat::Png, QrFormat::Svg] {
+            assert_eq!(db.qr_code(entry.id, format)?, Some(qr_code(uri, format)?));
+        }
+
+        let keyring = Keyring::from_public_key(&home.join("public.asc"))?;
+        let report = verify_database(
+            &db,
+            factory.descriptor.clone(),
+            factory.network,
+            &factory.message,
+            &factory.batches,
+            &keyring,
+        )?;
+        assert!(report.is_ok(), "{}", report);
+
+        // Or into a folder of their own
+        let qr_dir = dir.path().join("qr");
+        let options = GenerateOptions {
+            uris: Some(UriOptions {
+                qr_formats: vec![QrFormat::Svg],
+                qr_dir: Some(qr_dir.clone()),
+                ..UriOptions::default()
+            }),
+            ..GenerateOptions::default()
+        };
+        factory.generate_addresses(&mut db, &options)?;
+        let entry = &db.entries()?[2];
+        assert_eq!(db.qr_code(entry.id, QrFormat::Svg)?, None);
+        assert_eq!(
+            fs::read(qr_dir.join(format!("{}.svg", entry.address)))?,
+            qr_code(entry.uri.as_deref().unwrap(), QrFormat::Svg)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn generates_merkle_batches() -> Result<()> {
+        let home = test_gnupg_home();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ours.db");
+        let mut factory = test_factory(dir.path(), 3)?;
+
+        let mut db = Database::create(&path)?;
+        let options = GenerateOptions {
+            merkle: true,
+            ..GenerateOptions::default()
+        };
+        factory.generate_addresses(&mut db, &options)?;
+        drop(db);
+
+        // Nothing signs a row's statement on its own, so none is kept
+        let db = Database::open_read_only(&path)?;
+        assert!(db.entries()?.iter().all(|entry| entry.statement.is_none()));
+        let keyring = Keyring::from_public_key(&home.join("public.asc"))?;
+        let report = verify_signatures(&db, &keyring)?;
+        assert!(report.is_ok(), "{}", report);
+
+        // The signed root is only good for the batch it describes
+        Connection::open(&path)?.execute_batch(
+            "UPDATE batches SET count = 2, descriptor_checksum = 'aaaaaaaa';
+             UPDATE entries SET statement = 'Donate to Mallory' WHERE id = 1;",
+        )?;
+        let db = Database::open_read_only(&path)?;
+        let report = verify_signatures(&db, &keyring)?;
+        let fields: Vec<&str> = report
+            .problems
+            .iter()
+            .filter_map(|problem| match problem {
+                Problem::RootStatementMismatch { field, .. } => Some(*field),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(fields, vec!["Descriptor checksum", "Indexes"]);
+        assert!(report
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::UnsignedStatement { id: 1 })));
+
+        Ok(())
+    }
+}
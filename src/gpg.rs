@@ -3,41 +3,218 @@
 // This code is borrowed heavily from
 // https://doc.rust-lang.org/rust-by-example/std_misc/process/pipe.html
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use std::{
+    fs::{self, File},
     io::{Read, Write},
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
+use tempfile::TempDir;
 
 // TODO: Parallel sign
 // TODO: Option for user to cancel and retry with fewer addresses
 
+/// Clearsign any text: the statement for an address, something like
+/// "This is a donation address for me, Satoshi Nakamoto: <address>",
+/// or the statement committing to a whole batch
+pub fn gpg_clearsign_text(str_to_sign: &str) -> Result<String> {
+    // TODO: does this handle password input?
+    // maybe some inspo here: https://github.com/BurntSushi/rust-cmail/blob/master/cmail.rs
+    gpg_filter(&["--clear-sign"], str_to_sign, "sign")
+}
+
 /// Pass the address you want signed along with a friendly message
 /// Something like "This is a donation address for me, Satoshi Nakamoto:"
 pub fn gpg_clearsign(address: &str, message: &str) -> Result<String> {
-    // TODO: does this handle password input?
-    // maybe some inspo here: https://github.com/BurntSushi/rust-cmail/blob/master/cmail.rs
-    let process = Command::new("gpg")
-        .arg("--clear-sign")
+    gpg_clearsign_text(&format!("{} {}", message, address))
+}
+
+/// Encrypt text to `recipient` (a key ID or fingerprint), ASCII armored
+pub fn gpg_encrypt(text: &str, recipient: &str) -> Result<String> {
+    gpg_filter(
+        &["--encrypt", "--armor", "--recipient", recipient],
+        text,
+        "encrypt",
+    )
+}
+
+/// Decrypt an armored message with whichever of our secret keys it was encrypted to
+pub fn gpg_decrypt(message: &str) -> Result<String> {
+    gpg_filter(&["--decrypt", "--quiet"], message, "decrypt")
+}
+
+/// Whether text is an armored message from `gpg_encrypt`
+pub fn is_gpg_message(text: &str) -> bool {
+    text.trim_start().starts_with("-----BEGIN PGP MESSAGE-----")
+}
+
+/// Run gpg over `input` and return what it writes to stdout
+fn gpg_filter(args: &[&str], input: &str, action: &str) -> Result<String> {
+    let mut process = Command::new("gpg")
+        .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn();
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Write from another thread so gpg can't block on a full stdout while we're still writing
+    let mut stdin = process.stdin.take().unwrap();
+    let input = input.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    let output = process.wait_with_output()?;
+    writer.join().unwrap()?;
+    if !output.status.success() {
+        bail!(
+            "gpg couldn't {}: {}",
+            action,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
 
-    match process {
-        Ok(process) => {
-            let str_to_sign = format!("{} {}", message, address);
+    Ok(String::from_utf8(output.stdout)?)
+}
 
-            process.stdin.unwrap().write_all(str_to_sign.as_bytes())?;
+/// Fingerprint of the primary key `gpg_clearsign_text` signs with. Read back from
+/// a signature over a probe text, since `default-key` and friends decide that
+/// and the first secret key listed may not be it.
+pub fn gpg_signing_key() -> Result<String> {
+    let signed = gpg_clearsign_text("Which key signs this?")?;
+    let status = gpg_filter(&["--status-fd", "1", "--verify"], &signed, "verify")?;
 
-            let mut s = String::new();
-            process.stdout.unwrap().read_to_string(&mut s)?;
+    // VALIDSIG <signing key> ... <primary key>, the signing key may be a subkey
+    let fingerprint = status
+        .lines()
+        .find_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .and_then(|fields| fields.split_whitespace().last());
 
-            Ok(s)
-        }
-        Err(error) => {
-            eprintln!("{}", error);
-            eprintln!("Something went wrong with GPG");
-            Err(error.into())
+    match fingerprint {
+        Some(fingerprint) => Ok(fingerprint.to_string()),
+        None => bail!("Couldn't tell which key gpg signs with"),
+    }
+}
+
+/// Point gpg at a throwaway home with a fresh key that can sign and encrypt, once per
+/// test run, so tests never touch the real keyring. Its public key is in `public.asc`.
+#[cfg(test)]
+pub(crate) fn test_gnupg_home() -> &'static Path {
+    use std::sync::OnceLock;
+
+    static HOME: OnceLock<PathBuf> = OnceLock::new();
+    HOME.get_or_init(|| {
+        let home = tempfile::tempdir().unwrap().into_path();
+        // gpg complains about a home anyone else can read
+        fs::set_permissions(&home, std::os::unix::fs::PermissionsExt::from_mode(0o700)).unwrap();
+        std::env::set_var("GNUPGHOME", &home);
+
+        let generated = Command::new("gpg")
+            .args(["--batch", "--passphrase", "", "--quick-gen-key"])
+            .args(["Address Factory Test <test@example.com>", "future-default"])
+            .args(["default", "never"])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(generated.success(), "Couldn't generate a test key");
+
+        let exported = Command::new("gpg")
+            .args(["--armor", "--export"])
+            .output()
+            .unwrap();
+        fs::write(home.join("public.asc"), exported.stdout).unwrap();
+        home
+    })
+}
+
+/// A throwaway keyring holding the public key we check signatures against.
+/// Lives in a temp dir so verifying never touches the user's own keyring.
+pub struct Keyring {
+    dir: TempDir,
+    path: PathBuf,
+}
+
+impl Keyring {
+    /// Build a keyring from an exported public key (armored or binary)
+    pub fn from_public_key(public_key: &Path) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pubring.gpg");
+        let key = fs::read(public_key)?;
+
+        // gpgv only understands binary keyrings, so dearmor if we need to
+        if key.starts_with(b"-----BEGIN PGP") {
+            let output = Command::new("gpg")
+                .arg("--dearmor")
+                .arg("--output")
+                .arg(&path)
+                .arg(public_key)
+                .output()?;
+            if !output.status.success() {
+                bail!(
+                    "Couldn't read public key {}: {}",
+                    public_key.to_string_lossy(),
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        } else {
+            File::create(&path)?.write_all(&key)?;
         }
+
+        Ok(Self { dir, path })
+    }
+}
+
+/// Check a clearsigned message against the keyring and return the text that was signed
+pub fn gpg_verify(signed_message: &str, keyring: &Keyring) -> Result<String> {
+    let mut process = Command::new("gpgv")
+        .arg("--homedir")
+        .arg(keyring.dir.path())
+        .arg("--keyring")
+        .arg(&keyring.path)
+        .arg("--output")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    process
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(signed_message.as_bytes())?;
+
+    let mut s = String::new();
+    process.stdout.take().unwrap().read_to_string(&mut s)?;
+
+    if !process.wait()?.success() {
+        bail!("Bad or unknown signature");
+    }
+
+    // gpg hands back the signed text with a trailing newline we never signed
+    if s.ends_with('\n') {
+        s.pop();
+    }
+
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{gpg_clearsign, gpg_verify, test_gnupg_home, Keyring};
+
+    #[test]
+    fn clearsign_signs_message_then_address() -> Result<()> {
+        let home = test_gnupg_home();
+        let keyring = Keyring::from_public_key(&home.join("public.asc"))?;
+
+        let signed = gpg_clearsign("tb1qaddress", "Donate to Alice")?;
+        assert_eq!(
+            gpg_verify(&signed, &keyring)?.trim_end(),
+            "Donate to Alice tb1qaddress"
+        );
+
+        Ok(())
     }
 }
@@ -0,0 +1,164 @@
+// The factory's registry of every batch it has written, so there's a record of
+// which indexes went into which file long after the files have moved on.
+
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::sha256_file;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BatchRecord {
+    /// Absolute path of the database or SQL dump the batch went into
+    pub file: PathBuf,
+    pub start_index: u32,
+    pub count: u32,
+    pub created_at: String,
+    pub message: String,
+    pub signing_key_fingerprint: String,
+    /// SHA-256 of the file right after the batch was written
+    pub sha256: String,
+    #[serde(default)]
+    pub valid_until: Option<String>,
+}
+
+impl BatchRecord {
+    pub fn last_index(&self) -> u32 {
+        (self.start_index + self.count).saturating_sub(1)
+    }
+
+    fn overlaps(&self, other: &BatchRecord) -> bool {
+        self.count > 0
+            && other.count > 0
+            && self.start_index <= other.last_index()
+            && other.start_index <= self.last_index()
+    }
+}
+
+/// Something wrong with the registry. Batches are numbered from 1 in the order they were made.
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// Two batches used some of the same indexes, so the same addresses were handed out twice
+    Overlap { first: usize, second: usize },
+    /// The batch's file isn't where it was written
+    Missing { batch: usize, file: PathBuf },
+    /// The file has changed since the last batch went into it
+    Changed { batch: usize, file: PathBuf },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Overlap { first, second } => {
+                write!(f, "Batches {} and {} share indexes", first, second)
+            }
+            Problem::Missing { batch, file } => {
+                write!(f, "Batch {}: {} is missing", batch, file.to_string_lossy())
+            }
+            Problem::Changed { batch, file } => write!(
+                f,
+                "Batch {}: {} has changed since it was written",
+                batch,
+                file.to_string_lossy()
+            ),
+        }
+    }
+}
+
+/// Look for overlapping ranges and missing or changed files.
+/// Only the last batch written to each file is checked against its hash.
+pub fn problems(records: &[BatchRecord]) -> Result<Vec<Problem>> {
+    let mut problems = vec![];
+
+    for (i, record) in records.iter().enumerate() {
+        for (j, other) in records.iter().enumerate().skip(i + 1) {
+            if record.overlaps(other) {
+                problems.push(Problem::Overlap {
+                    first: i + 1,
+                    second: j + 1,
+                });
+            }
+        }
+    }
+
+    let mut latest = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        latest.insert(&record.file, i);
+    }
+    for (i, record) in records.iter().enumerate() {
+        let batch = i + 1;
+        let file = record.file.clone();
+        if !record.file.is_file() {
+            problems.push(Problem::Missing { batch, file });
+        } else if latest[&record.file] == i
+            && sha256_file(&record.file)?.to_string() != record.sha256
+        {
+            problems.push(Problem::Changed { batch, file });
+        }
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use anyhow::Result;
+
+    use super::{problems, BatchRecord, Problem};
+    use crate::manifest::sha256_file;
+
+    fn record(file: &Path, start_index: u32, count: u32) -> Result<BatchRecord> {
+        Ok(BatchRecord {
+            file: file.to_path_buf(),
+            start_index,
+            count,
+            created_at: "2021-03-20T12:00:00+00:00".to_string(),
+            message: "Donate to Alice".to_string(),
+            signing_key_fingerprint: "ABCD".to_string(),
+            sha256: sha256_file(file)?.to_string(),
+            valid_until: None,
+        })
+    }
+
+    #[test]
+    fn finds_overlaps_and_missing_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = dir.path().join("first.db");
+        let second = dir.path().join("second.db");
+        fs::write(&first, "one")?;
+        fs::write(&second, "two")?;
+
+        let mut records = vec![record(&first, 0, 1000)?, record(&second, 1000, 1000)?];
+        assert_eq!(problems(&records)?, vec![]);
+
+        // Appending to the first file changes it, but only its latest batch is checked
+        fs::write(&first, "one and three")?;
+        records.push(record(&first, 1500, 100)?);
+        fs::remove_file(&second)?;
+
+        assert_eq!(
+            problems(&records)?,
+            vec![
+                Problem::Overlap {
+                    first: 2,
+                    second: 3
+                },
+                Problem::Missing {
+                    batch: 2,
+                    file: second
+                },
+            ]
+        );
+
+        fs::write(&first, "edited")?;
+        assert!(problems(&records)?.contains(&Problem::Changed {
+            batch: 3,
+            file: first
+        }));
+
+        Ok(())
+    }
+}
@@ -1,12 +1,35 @@
+pub mod alert;
+pub mod bip21;
 mod coldcard;
 mod database;
+pub mod derive;
+pub mod export;
 mod factory_state;
 mod gpg;
+pub mod history;
+pub mod manifest;
+pub mod merkle;
+pub mod purge;
+pub mod qr;
+pub mod server;
+pub mod site;
+pub mod store;
+pub mod template;
 pub mod util;
+mod verify;
+pub mod wallet_hints;
+pub mod watch;
+pub mod watchonly;
+pub mod webhook;
 pub mod wizard_steps;
 
 pub use coldcard::ColdcardJson;
-pub use database::{Database, Entry};
-pub use factory_state::Factory;
-pub use gpg::gpg_clearsign;
+pub use database::{Batch, Database, Entry, Payment, PoolLevel, DEFAULT_LOW_WATER};
+pub use factory_state::{Factory, GenerateOptions, UriOptions};
+pub use gpg::{
+    gpg_clearsign, gpg_clearsign_text, gpg_decrypt, gpg_encrypt, gpg_signing_key, gpg_verify,
+    is_gpg_message, Keyring,
+};
+pub use store::Store;
 pub use util::Desc;
+pub use verify::{verify_address_proof, verify_database, verify_signatures, Problem, Report};
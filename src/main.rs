@@ -1,5 +1,19 @@
-use address_factory::wizard_steps::*;
+use address_factory::{
+    alert::RefillAlert,
+    bip21::PaymentRequest,
+    export::{ExportFormat, ExportOptions},
+    purge::PurgeOptions,
+    qr::QrFormat,
+    site::SiteOptions,
+    store::SqlDialect,
+    watch::WatchOptions,
+    webhook::Webhook,
+    wizard_steps::*,
+    GenerateOptions, UriOptions, DEFAULT_LOW_WATER,
+};
 use anyhow::Result;
+use bdk::bitcoin::{util::amount::Denomination, Amount};
+use chrono::NaiveDate;
 use std::path::PathBuf;
 
 use clap::Clap;
@@ -9,10 +23,213 @@ use directories::ProjectDirs;
 #[derive(Clap)]
 #[clap(version = "0.1 Alpha", author = "Paul M. <paul@paul.lol>")]
 struct Opts {
+    /// Delete your config and the databases Address Factory made in this folder
     #[clap(long)]
     purge: bool,
+    /// With --purge, list what would go without touching anything
+    #[clap(long, requires = "purge")]
+    dry_run: bool,
+    /// With --purge, move the files into a folder here instead of deleting them
+    #[clap(long, requires = "purge")]
+    archive: Option<PathBuf>,
+    /// Where to write the database: a file, or a directory for a timestamped file
+    #[clap(long, conflicts_with = "append")]
+    output: Option<PathBuf>,
+    /// Add the new batch to an existing database instead of creating one
+    #[clap(long)]
+    append: Option<PathBuf>,
+    /// Write the batch as SQL INSERT statements to load into another database
+    #[clap(long, conflicts_with_all = &["append", "output"])]
+    sql_dump: Option<PathBuf>,
+    /// SQL flavour for --sql-dump: postgres (the default) or mysql
+    #[clap(long, requires = "sql-dump")]
+    sql_dialect: Option<SqlDialect>,
+    /// Sign one Merkle root over the batch instead of every address
+    #[clap(long)]
+    merkle: bool,
+    /// Save address-factory.json encrypted to your signing key from now on
+    #[clap(long)]
+    encrypt_config: bool,
+    /// Include the full descriptor in the signed manifest (anyone with it can see all your addresses)
+    #[clap(long)]
+    manifest_descriptor: bool,
+    /// Last day to hand the addresses out, YYYY-MM-DD. Signed with each one.
+    #[clap(long)]
+    valid_until: Option<NaiveDate>,
+    /// Store a BIP21 payment URI with each address
+    #[clap(long)]
+    uri: bool,
+    /// Amount to ask for in the payment URIs, in BTC
+    #[clap(long, requires = "uri")]
+    uri_amount: Option<String>,
+    /// Label for the payment URIs (defaults to the signed message)
+    #[clap(long, requires = "uri")]
+    uri_label: Option<String>,
+    /// Message for the payment URIs
+    #[clap(long, requires = "uri")]
+    uri_message: Option<String>,
+    /// Render a QR code of each payment URI (png or svg, repeat for both)
+    #[clap(long, requires = "uri", number_of_values = 1)]
+    qr: Vec<QrFormat>,
+    /// Write the QR codes into this folder instead of the database
+    #[clap(long, requires = "qr")]
+    qr_dir: Option<PathBuf>,
     coldcard_json: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Check every address and signature in a signed addresses database
+    Verify {
+        database: PathBuf,
+        /// Exported public key of the signer (gpg --armor --export <key ID>)
+        #[clap(long)]
+        public_key: PathBuf,
+        /// Message for batches missing from the factory's history (defaults to the factory's)
+        #[clap(long)]
+        message: Option<String>,
+    },
+    /// Check one address against the signed Merkle root of its batch
+    VerifyAddress {
+        address: String,
+        /// Derivation index of the address
+        #[clap(long)]
+        index: u32,
+        /// The address's Merkle proof
+        #[clap(long)]
+        proof: String,
+        /// File holding the batch's signed root
+        #[clap(long)]
+        signed_root: PathBuf,
+        /// Exported public key of the signer (gpg --armor --export <key ID>)
+        #[clap(long)]
+        public_key: PathBuf,
+    },
+    /// Check a signed manifest and the database next to it
+    VerifyManifest {
+        manifest: PathBuf,
+        /// Exported public key of the signer (gpg --armor --export <key ID>)
+        #[clap(long)]
+        public_key: PathBuf,
+    },
+    /// Hand out signed addresses from a database over HTTP
+    Serve {
+        database: PathBuf,
+        /// Address and port to listen on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+        #[clap(flatten)]
+        alert: AlertOpts,
+    },
+    /// Show how many addresses are left and warn when it's time to generate more
+    Status {
+        database: PathBuf,
+        #[clap(flatten)]
+        alert: AlertOpts,
+    },
+    /// Check the chain for payments to a database's addresses and record them
+    Watch {
+        database: PathBuf,
+        /// Electrum server (tcp://host:port or ssl://host:port) or Esplora API (https://...)
+        #[clap(long)]
+        backend: String,
+        /// Only check addresses that have been handed out
+        #[clap(long)]
+        dispensed: bool,
+        /// Keep checking every this many seconds instead of once
+        #[clap(long)]
+        interval: Option<u64>,
+        /// POST payments to dispensed addresses here as JSON
+        #[clap(long)]
+        webhook: Option<String>,
+        /// Sign webhook bodies with HMAC-SHA256 using this secret
+        #[clap(long, requires = "webhook")]
+        webhook_secret: Option<String>,
+        /// Confirmations before sending payment.confirmed [default: 1]
+        #[clap(long, requires = "webhook")]
+        confirmations: Option<u32>,
+        /// Retries when the webhook fails, backing off exponentially [default: 5]
+        #[clap(long, requires = "webhook")]
+        webhook_retries: Option<u32>,
+    },
+    /// Render a database as static pages for any web host
+    ExportSite {
+        database: PathBuf,
+        /// Folder to write the site into
+        #[clap(long)]
+        out: PathBuf,
+        #[clap(long, default_value = "Donate")]
+        title: String,
+        /// Label for the payment links, e.g. your name
+        #[clap(long)]
+        label: Option<String>,
+    },
+    /// Write a database's addresses to a CSV, JSON Lines or plain text file
+    Export {
+        database: PathBuf,
+        /// File to write
+        #[clap(long)]
+        out: PathBuf,
+        /// csv, jsonl, txt or bip329 (wallet labels)
+        #[clap(long, default_value = "csv")]
+        format: ExportFormat,
+        /// Only export this batch
+        #[clap(long)]
+        batch: Option<i64>,
+        /// Also write each signed message to its own .asc file
+        #[clap(long)]
+        signatures: bool,
+        /// Start each BIP-329 label with this instead of each address's signed text
+        #[clap(long)]
+        message: Option<String>,
+    },
+    /// Write watch-only wallet files for every address generated so far
+    ExportWatchonly {
+        /// Folder to write the wallet files into
+        #[clap(long)]
+        out: PathBuf,
+        /// UNIX time for Bitcoin Core to rescan from, or "now"
+        #[clap(long, default_value = "now")]
+        timestamp: String,
+    },
+    /// List every batch the factory has written, checking for overlapping ranges and missing files
+    History,
+}
+
+/// When and how to warn that a database is running out of addresses
+#[derive(Clap)]
+struct AlertOpts {
+    /// Warn once fewer than this many unused addresses are left [default: 100]
+    #[clap(long)]
+    low_water: Option<u32>,
+    /// POST a pool.low event here when the pool runs low
+    #[clap(long)]
+    alert_webhook: Option<String>,
+    /// Sign the alert webhook's body with HMAC-SHA256 using this secret
+    #[clap(long, requires = "alert-webhook")]
+    alert_webhook_secret: Option<String>,
+    /// Append an email-style alert to this file when the pool runs low
+    #[clap(long)]
+    alert_file: Option<PathBuf>,
 }
+
+impl AlertOpts {
+    fn into_alert(self) -> RefillAlert {
+        let secret = self.alert_webhook_secret;
+        RefillAlert {
+            low_water: self.low_water.unwrap_or(DEFAULT_LOW_WATER),
+            webhook: self.alert_webhook.map(|url| {
+                let mut webhook = Webhook::new(&url);
+                webhook.secret = secret;
+                webhook
+            }),
+            file: self.alert_file,
+        }
+    }
+}
+
 // The basic logic:
 //
 // CONTINUE EXISTING SETUP
@@ -43,10 +260,99 @@ fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
 
     if opts.purge {
-        purge(config_dir)?;
+        purge(
+            config_dir,
+            PurgeOptions {
+                dry_run: opts.dry_run,
+                archive: opts.archive,
+            },
+        )?;
         return Ok(());
     }
 
+    if let Some(command) = opts.command {
+        return match command {
+            Command::Verify {
+                database,
+                public_key,
+                message,
+            } => verify(path_to_config, &database, &public_key, message),
+            Command::VerifyAddress {
+                address,
+                index,
+                proof,
+                signed_root,
+                public_key,
+            } => verify_address(&address, index, &proof, &signed_root, &public_key),
+            Command::VerifyManifest {
+                manifest,
+                public_key,
+            } => verify_manifest(&manifest, &public_key),
+            Command::Serve {
+                database,
+                bind,
+                alert,
+            } => serve(&database, &bind, alert.into_alert()),
+            Command::Status { database, alert } => status(&database, alert.into_alert()),
+            Command::Watch {
+                database,
+                backend,
+                dispensed,
+                interval,
+                webhook,
+                webhook_secret,
+                confirmations,
+                webhook_retries,
+            } => {
+                let webhook = webhook.map(|url| {
+                    let mut webhook = Webhook::new(&url);
+                    webhook.secret = webhook_secret;
+                    webhook.confirmations = confirmations.unwrap_or(webhook.confirmations);
+                    webhook.retries = webhook_retries.unwrap_or(webhook.retries);
+                    webhook
+                });
+                watch(
+                    &database,
+                    &backend,
+                    WatchOptions {
+                        dispensed_only: dispensed,
+                        webhook,
+                    },
+                    interval,
+                )
+            }
+            Command::ExportSite {
+                database,
+                out,
+                title,
+                label,
+            } => export_site(&database, &out, SiteOptions { title, label }),
+            Command::Export {
+                database,
+                out,
+                format,
+                batch,
+                signatures,
+                message,
+            } => export(
+                path_to_config,
+                &database,
+                &out,
+                ExportOptions {
+                    format,
+                    batch,
+                    signatures,
+                    message,
+                    factory_message: None,
+                },
+            ),
+            Command::ExportWatchonly { out, timestamp } => {
+                export_watchonly(path_to_config, &out, &timestamp)
+            }
+            Command::History => history(path_to_config),
+        };
+    }
+
     // Create our factory object from all sorts of scenarios
     let mut factory = if path_to_config.exists() {
         println!(
@@ -87,5 +393,35 @@ fn main() -> Result<()> {
         }
     };
 
-    execute(&mut factory)
+    if opts.encrypt_config {
+        factory.encrypted = true;
+    }
+
+    let uris = if opts.uri {
+        let amount = match opts.uri_amount {
+            Some(amount) => Some(Amount::from_str_in(&amount, Denomination::Bitcoin)?),
+            None => None,
+        };
+        Some(UriOptions {
+            request: PaymentRequest {
+                label: opts.uri_label,
+                message: opts.uri_message,
+                amount,
+            },
+            qr_formats: opts.qr,
+            qr_dir: opts.qr_dir,
+        })
+    } else {
+        None
+    };
+
+    let dialect = opts.sql_dialect.unwrap_or(SqlDialect::Postgres);
+    let mut store = open_store(opts.append, opts.output, opts.sql_dump, dialect)?;
+    let options = GenerateOptions {
+        uris,
+        merkle: opts.merkle,
+        manifest_descriptor: opts.manifest_descriptor,
+        valid_until: opts.valid_until,
+    };
+    execute(&mut factory, store.as_mut(), options)
 }
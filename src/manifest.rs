@@ -0,0 +1,178 @@
+// A signed summary of what's in a database, written next to it after each
+// run. Whoever deploys the database can check the file on the server is the
+// one that was signed, without needing the xpub.
+
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use bdk::bitcoin::hashes::{sha256, Hash, HashEngine};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::{gpg_clearsign_text, gpg_verify, Batch, Desc, Keyring};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// File name of the database, expected next to the manifest
+    pub file: String,
+    pub sha256: String,
+    pub network: String,
+    pub descriptor_checksum: String,
+    /// Only there if asked for, it reveals every address the wallet will ever use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descriptor: Option<String>,
+    pub batches: Vec<BatchSummary>,
+    pub tool_version: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchSummary {
+    pub id: i64,
+    pub first_index: u32,
+    pub last_index: u32,
+    pub count: u32,
+    pub first_address: String,
+    pub last_address: String,
+    pub signing_key_fingerprint: String,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<String>,
+}
+
+impl BatchSummary {
+    pub fn new(batch: &Batch, first_address: &str, last_address: &str) -> Self {
+        Self {
+            id: batch.id,
+            first_index: batch.start_index,
+            last_index: batch.start_index + batch.count.saturating_sub(1),
+            count: batch.count,
+            first_address: first_address.to_string(),
+            last_address: last_address.to_string(),
+            signing_key_fingerprint: batch.signing_key_fingerprint.clone(),
+            created_at: batch.created_at.clone(),
+            merkle_root: batch.merkle_root.clone(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Summarise `file` as it is now. Call once nothing else will be written to it.
+    pub fn new(file: &Path, batches: Vec<BatchSummary>, first: &Batch) -> Result<Self> {
+        Ok(Self {
+            file: file
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            sha256: sha256_file(file)?.to_string(),
+            network: first.network.clone(),
+            descriptor_checksum: first.descriptor_checksum.clone(),
+            descriptor: None,
+            batches,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: Local::now().to_rfc3339(),
+        })
+    }
+
+    pub fn with_descriptor(mut self, descriptor: &Desc) -> Self {
+        self.descriptor = Some(descriptor.to_string());
+        self
+    }
+
+    /// Clearsign the manifest and write it to `<file>.manifest.asc` next to the database
+    pub fn write(&self, file: &Path) -> Result<PathBuf> {
+        let path = manifest_path(file);
+        let signed = gpg_clearsign_text(&serde_json::to_string_pretty(self)?)?;
+        std::fs::write(&path, signed)?;
+        Ok(path)
+    }
+}
+
+pub fn manifest_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(".manifest.asc");
+    file.with_file_name(name)
+}
+
+/// Check a manifest's signature and that the file it describes, in the
+/// same folder, still has the hash that was signed
+pub fn verify_manifest(path: &Path, keyring: &Keyring) -> Result<Manifest> {
+    let signed = std::fs::read_to_string(path)?;
+    let manifest: Manifest = match gpg_verify(&signed, keyring) {
+        Ok(text) => serde_json::from_str(&text)?,
+        Err(_) => bail!("Bad signature on {}", path.to_string_lossy()),
+    };
+
+    let file = path.with_file_name(&manifest.file);
+    let sha256 = sha256_file(&file)?.to_string();
+    if sha256 != manifest.sha256 {
+        bail!(
+            "{} has SHA-256 {} but the manifest says {}",
+            file.to_string_lossy(),
+            sha256,
+            manifest.sha256
+        )
+    }
+
+    Ok(manifest)
+}
+
+pub fn sha256_file(path: &Path) -> Result<sha256::Hash> {
+    let mut file = File::open(path)?;
+    let mut engine = sha256::Hash::engine();
+    let mut buffer = [0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        engine.input(&buffer[..read]);
+    }
+
+    Ok(sha256::Hash::from_engine(engine))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use anyhow::Result;
+
+    use super::{manifest_path, sha256_file, BatchSummary, Manifest};
+    use crate::database::test_batch;
+
+    #[test]
+    fn summarises_a_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("ours.db");
+        fs::write(&file, "abc")?;
+
+        assert_eq!(
+            sha256_file(&file)?.to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let batch = test_batch();
+        let summary = BatchSummary::new(&batch, "tb1qfirst", "tb1qsecond");
+        assert_eq!(summary.last_index, 1);
+
+        let manifest = Manifest::new(&file, vec![summary], &batch)?;
+        assert_eq!(manifest.file, "ours.db");
+        let json = serde_json::to_value(&manifest)?;
+        assert!(json.get("descriptor").is_none());
+        assert_eq!(json["batches"][0]["first_address"], "tb1qfirst");
+
+        assert_eq!(
+            manifest_path(Path::new("out/ours.db")),
+            Path::new("out/ours.db.manifest.asc")
+        );
+
+        Ok(())
+    }
+}
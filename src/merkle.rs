@@ -0,0 +1,238 @@
+// Commit to a whole batch of addresses with one signature. Each address and
+// its index is a leaf of a Merkle tree, the root gets signed once, and every
+// entry keeps the proof that it's in the tree.
+//
+// Leaves and inner nodes are hashed with different prefixes so a leaf can't
+// pass for a node. An odd node out moves up a level as is, it isn't paired
+// with itself.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use bdk::bitcoin::hashes::{sha256, Hash, HashEngine};
+
+use crate::{template::VALID_UNTIL, Batch};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn leaf_hash(index: u32, address: &str) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[LEAF_PREFIX]);
+    engine.input(&index.to_be_bytes());
+    engine.input(address.as_bytes());
+    sha256::Hash::from_engine(engine)
+}
+
+fn node_hash(left: &sha256::Hash, right: &sha256::Hash) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[NODE_PREFIX]);
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+    sha256::Hash::from_engine(engine)
+}
+
+pub struct MerkleTree {
+    /// Leaves first, the root alone in the last level
+    levels: Vec<Vec<sha256::Hash>>,
+}
+
+impl MerkleTree {
+    /// Build a tree over `(index, address)` pairs, in order
+    pub fn new<'a>(leaves: impl IntoIterator<Item = (u32, &'a str)>) -> Result<Self> {
+        let leaves: Vec<sha256::Hash> = leaves
+            .into_iter()
+            .map(|(index, address)| leaf_hash(index, address))
+            .collect();
+        if leaves.is_empty() {
+            bail!("Can't build a Merkle tree without any addresses")
+        }
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [odd] => *odd,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(level);
+        }
+
+        Ok(Self { levels })
+    }
+
+    pub fn root(&self) -> sha256::Hash {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// The proof for the leaf at `position` (not its derivation index)
+    pub fn proof(&self, position: usize) -> Proof {
+        let mut steps = vec![];
+        let mut position = position;
+
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                steps.push(if sibling < position {
+                    Step::Left(*hash)
+                } else {
+                    Step::Right(*hash)
+                });
+            }
+            position /= 2;
+        }
+
+        Proof { steps }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    /// The sibling goes on the left of the running hash
+    Left(sha256::Hash),
+    Right(sha256::Hash),
+}
+
+/// The sibling hashes from a leaf up to the root
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proof {
+    pub steps: Vec<Step>,
+}
+
+impl Proof {
+    /// The root you get by hashing this address up through the proof
+    pub fn root_for(&self, index: u32, address: &str) -> sha256::Hash {
+        self.steps
+            .iter()
+            .fold(leaf_hash(index, address), |hash, step| match step {
+                Step::Left(sibling) => node_hash(sibling, &hash),
+                Step::Right(sibling) => node_hash(&hash, sibling),
+            })
+    }
+
+    pub fn verify(&self, index: u32, address: &str, root: &sha256::Hash) -> bool {
+        self.root_for(index, address) == *root
+    }
+}
+
+/// Stored as comma-separated steps, `L` or `R` then the sibling's hex hash
+impl std::fmt::Display for Proof {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let steps: Vec<String> = self
+            .steps
+            .iter()
+            .map(|step| match step {
+                Step::Left(hash) => format!("L{}", hash),
+                Step::Right(hash) => format!("R{}", hash),
+            })
+            .collect();
+        write!(f, "{}", steps.join(","))
+    }
+}
+
+impl FromStr for Proof {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut steps = vec![];
+
+        for step in s.split(',').filter(|step| !step.is_empty()) {
+            let hash = match step.get(1..) {
+                Some(hex) => sha256::Hash::from_str(hex)?,
+                None => bail!("Bad proof step {}", step),
+            };
+            steps.push(match &step[..1] {
+                "L" => Step::Left(hash),
+                "R" => Step::Right(hash),
+                _ => bail!("Bad proof step {}", step),
+            });
+        }
+
+        Ok(Self { steps })
+    }
+}
+
+/// The text that gets signed for a batch in Merkle mode
+pub fn root_statement(message: &str, batch: &Batch, root: &sha256::Hash) -> String {
+    let valid_until = match &batch.valid_until {
+        Some(valid_until) => format!("\n{}{}", VALID_UNTIL, valid_until),
+        None => String::new(),
+    };
+    format!(
+        "{}\nNetwork: {}\nDescriptor checksum: {}\nIndexes: {}..{}{}\nMerkle root: {}",
+        message,
+        batch.network,
+        batch.descriptor_checksum,
+        batch.start_index,
+        batch.start_index + batch.count,
+        valid_until,
+        root
+    )
+}
+
+/// Pull the root back out of a signed statement
+pub fn root_from_statement(statement: &str) -> Result<sha256::Hash> {
+    match statement_field(statement, "Merkle root") {
+        Some(root) => Ok(sha256::Hash::from_str(root.trim())?),
+        None => bail!("No Merkle root in the signed statement"),
+    }
+}
+
+/// One of the lines `root_statement` adds after the message. The last one wins,
+/// so a message with a line that looks the same can't stand in for it.
+pub fn statement_field<'a>(statement: &'a str, name: &str) -> Option<&'a str> {
+    statement.lines().rev().find_map(|line| {
+        line.strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix(": "))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{root_from_statement, root_statement, statement_field, MerkleTree, Proof};
+    use crate::database::test_batch;
+
+    #[test]
+    fn proves_every_leaf() -> Result<()> {
+        let addresses: Vec<String> = (0..7).map(|i| format!("tb1qaddress{}", i)).collect();
+        let leaves = || (100..).zip(addresses.iter().map(String::as_str));
+
+        for count in 1..=addresses.len() {
+            let tree = MerkleTree::new(leaves().take(count))?;
+            let root = tree.root();
+
+            for (position, (index, address)) in leaves().take(count).enumerate() {
+                let proof: Proof = tree.proof(position).to_string().parse()?;
+                assert!(proof.verify(index, address, &root));
+                // Same address at another index, or another address, doesn't prove
+                assert!(!proof.verify(index + 1, address, &root));
+                assert!(!proof.verify(index, "tb1qsomeoneelse", &root));
+            }
+        }
+
+        assert!(MerkleTree::new(leaves().take(0)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn root_survives_the_statement() -> Result<()> {
+        let tree = MerkleTree::new(vec![(0, "tb1qfirst"), (1, "tb1qsecond")])?;
+        let statement = root_statement("Donations to me", &test_batch(), &tree.root());
+
+        assert_eq!(statement_field(&statement, "Indexes"), Some("0..2"));
+        assert_eq!(root_from_statement(&statement)?, tree.root());
+
+        let statement = root_statement("Network: mainnet", &test_batch(), &tree.root());
+        assert_eq!(statement_field(&statement, "Network"), Some("testnet"));
+
+        Ok(())
+    }
+}
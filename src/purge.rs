@@ -0,0 +1,233 @@
+// Work out exactly which files a purge would remove, so it only ever touches
+// files Address Factory made itself.
+
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use chrono::Local;
+
+use crate::{
+    history::BatchRecord,
+    manifest::{manifest_path, sha256_file},
+    Database,
+};
+
+/// Suffix `Database::default_path` gives every database
+const DATABASE_SUFFIX: &str = "_signed_addresses.db";
+
+#[derive(Debug, Default)]
+pub struct PurgeOptions {
+    /// Only list what would go
+    pub dry_run: bool,
+    /// Move the files into a timestamped folder here instead of deleting them
+    pub archive: Option<PathBuf>,
+}
+
+/// The config file plus every database in `dir` that has our naming scheme and
+/// schema, and every file in the factory's registry that's still ours, along
+/// with the files SQLite and the manifest keep next to them
+pub fn files_to_purge(
+    config_file: &Path,
+    dir: &Path,
+    registry: &[BatchRecord],
+) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    if config_file.is_file() {
+        files.push(config_file.to_path_buf());
+    }
+
+    let mut databases = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let named_like_ours = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().ends_with(DATABASE_SUFFIX));
+        if path.is_file() && named_like_ours && Database::is_ours(&path) {
+            databases.push(fs::canonicalize(path)?);
+        }
+    }
+    // SQL dumps can't be told apart by their contents, so those have to match
+    // the hash they were registered with
+    for record in registry {
+        let file = &record.file;
+        if file.is_file()
+            && !databases.contains(file)
+            && (Database::is_ours(file) || sha256_file(file)?.to_string() == record.sha256)
+        {
+            databases.push(file.clone());
+        }
+    }
+    databases.sort();
+
+    for database in databases {
+        let mut sidecars = vec![manifest_path(&database)];
+        for suffix in &["-wal", "-shm"] {
+            let mut name = database.as_os_str().to_os_string();
+            name.push(suffix);
+            sidecars.push(PathBuf::from(name));
+        }
+
+        files.push(database);
+        files.extend(sidecars.into_iter().filter(|path| path.is_file()));
+    }
+
+    Ok(files)
+}
+
+/// Delete the files, or move them into a new folder under `archive`. Returns that folder.
+/// Archived files keep their whole path under it, since files from different
+/// folders can share a name.
+pub fn remove_or_archive(files: &[PathBuf], archive: Option<&Path>) -> Result<Option<PathBuf>> {
+    let archive = match archive {
+        Some(archive) => {
+            let folder = archive.join(Local::now().format("%Y-%m-%d_%H-%M-%S").to_string());
+            if folder.exists() {
+                bail!("{} already exists", folder.to_string_lossy())
+            }
+            fs::create_dir_all(&folder)?;
+            Some(folder)
+        }
+        None => None,
+    };
+
+    for file in files {
+        match &archive {
+            Some(folder) => {
+                let path: PathBuf = fs::canonicalize(file)?
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect();
+                let target = folder.join(path);
+                if target.exists() {
+                    bail!("{} is already in the archive", file.to_string_lossy())
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // Renaming doesn't work across filesystems, copying does
+                if fs::rename(file, &target).is_err() {
+                    fs::copy(file, &target)?;
+                    fs::remove_file(file)?;
+                }
+            }
+            None => fs::remove_file(file)?,
+        }
+    }
+
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use anyhow::Result;
+    use rusqlite::{params, Connection};
+
+    use super::{files_to_purge, remove_or_archive};
+    use crate::{history::BatchRecord, manifest::sha256_file, Database};
+
+    #[test]
+    fn only_purges_our_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = dir.path().join("address-factory.json");
+        fs::write(&config, "{}")?;
+
+        let ours = dir.path().join("2021-03-20_12-00-00_signed_addresses.db");
+        Database::create(&ours)?;
+        fs::write(
+            dir.path()
+                .join("2021-03-20_12-00-00_signed_addresses.db.manifest.asc"),
+            "",
+        )?;
+        // Somebody else's databases, one even named like ours
+        Connection::open(dir.path().join("app.db"))?
+            .execute("CREATE TABLE stuff (id INTEGER)", params![])?;
+        Connection::open(dir.path().join("x_signed_addresses.db"))?
+            .execute("CREATE TABLE stuff (id INTEGER)", params![])?;
+
+        // A dump the factory wrote somewhere else, and one edited since
+        let elsewhere = tempfile::tempdir()?;
+        let dump = elsewhere.path().join("batch.sql");
+        let edited = elsewhere.path().join("edited.sql");
+        fs::write(&dump, "INSERT INTO entries;")?;
+        fs::write(&edited, "INSERT INTO entries;")?;
+        let registry: Vec<BatchRecord> = [&dump, &edited]
+            .iter()
+            .map(|file| BatchRecord {
+                file: file.canonicalize().unwrap(),
+                start_index: 0,
+                count: 10,
+                created_at: String::new(),
+                message: String::new(),
+                signing_key_fingerprint: String::new(),
+                sha256: sha256_file(file).unwrap().to_string(),
+                valid_until: None,
+            })
+            .collect();
+        fs::write(&edited, "DROP TABLE entries;")?;
+
+        let files = files_to_purge(&config, dir.path(), &registry)?;
+        let names: Vec<String> = files
+            .iter()
+            .map(|file| file.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names[0], "address-factory.json");
+        assert!(names.contains(&"2021-03-20_12-00-00_signed_addresses.db".to_string()));
+        assert!(names.contains(&"2021-03-20_12-00-00_signed_addresses.db.manifest.asc".to_string()));
+        assert!(names.contains(&"batch.sql".to_string()));
+        assert!(!names.iter().any(|name| name == "app.db"
+            || name == "x_signed_addresses.db"
+            || name == "edited.sql"));
+
+        let archive = dir.path().join("archive");
+        let folder = remove_or_archive(&files, Some(&archive))?.unwrap();
+        assert!(!ours.exists());
+        assert!(archived(
+            &folder,
+            &dir.path().join("2021-03-20_12-00-00_signed_addresses.db")
+        )
+        .exists());
+        assert!(dir.path().join("app.db").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn archives_files_with_the_same_name_apart() -> Result<()> {
+        let first = tempfile::tempdir()?;
+        let second = tempfile::tempdir()?;
+        let files = vec![
+            first.path().join("batch.sql"),
+            second.path().join("batch.sql"),
+        ];
+        fs::write(&files[0], "first")?;
+        fs::write(&files[1], "second")?;
+
+        let archive = tempfile::tempdir()?;
+        let folder = remove_or_archive(&files, Some(archive.path()))?.unwrap();
+        assert_eq!(fs::read_to_string(archived(&folder, &files[0]))?, "first");
+        assert_eq!(fs::read_to_string(archived(&folder, &files[1]))?, "second");
+        assert!(!files[0].exists() && !files[1].exists());
+
+        Ok(())
+    }
+
+    /// Where `file` ends up in the archive folder
+    fn archived(folder: &Path, file: &Path) -> PathBuf {
+        // The file itself has moved, but its folder is still there
+        let path = file
+            .parent()
+            .unwrap()
+            .canonicalize()
+            .unwrap()
+            .join(file.file_name().unwrap());
+        folder.join(path.strip_prefix("/").unwrap())
+    }
+}
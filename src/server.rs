@@ -0,0 +1,332 @@
+// A tiny single-threaded HTTP server so a donation page can hand out
+// a fresh signed address to every visitor.
+
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::{
+    alert::{refill_message, RefillAlert},
+    Database, Entry,
+};
+
+/// Serve addresses from `db` until the process is killed
+pub fn serve(db: Database, address: &str, alert: RefillAlert) -> Result<()> {
+    let server =
+        Server::http(address).map_err(|error| anyhow!("Can't listen on {}: {}", address, error))?;
+
+    println!(
+        "Serving addresses from {} on http://{}",
+        db.filename, address
+    );
+    println!("GET /address       next unused address");
+    println!("GET /address/<id>  an address that's already been handed out");
+    println!("GET /health        how many addresses are left");
+
+    run(server, db, alert)
+}
+
+fn run(server: Server, mut db: Database, alert: RefillAlert) -> Result<()> {
+    let json = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    // Alert once each time the pool drops below the mark, not on every request after
+    let mut alerted = false;
+
+    for request in server.incoming_requests() {
+        let (status, body) = route(&mut db, request.method(), request.url(), alert.low_water);
+        let dispensed = dispenses(request.method(), request.url());
+
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(json.clone());
+
+        if let Err(error) = request.respond(response) {
+            eprintln!("Couldn't send response: {}", error);
+        }
+
+        // After responding, so the visitor isn't kept waiting on the alert
+        if dispensed {
+            check_pool(&db, &alert, &mut alerted);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_pool(db: &Database, alert: &RefillAlert, alerted: &mut bool) {
+    let level = match db.pool_level(alert.low_water) {
+        Ok(level) => level,
+        Err(error) => return eprintln!("Couldn't check how many addresses are left: {}", error),
+    };
+
+    if !level.low {
+        *alerted = false;
+    } else if !*alerted {
+        eprintln!("{}", refill_message(&db.filename, &level));
+        // The webhook retries with backoff, which mustn't hold up the next request
+        let alert = alert.clone();
+        let database = db.filename.clone();
+        thread::spawn(move || {
+            if let Err(error) = alert.fire(&database, &level) {
+                eprintln!("Couldn't send the low-water alert: {}", error);
+            }
+        });
+        *alerted = true;
+    }
+}
+
+/// Whether the request hands out an address, so the pool may have run low
+fn dispenses(method: &Method, url: &str) -> bool {
+    *method == Method::Get && split_url(url).0 == "/address"
+}
+
+/// The path and query string, which may be empty
+fn split_url(url: &str) -> (&str, &str) {
+    url.split_once('?').unwrap_or((url, ""))
+}
+
+/// Work out the response for a request. Kept apart from the server so it's easy to test.
+pub fn route(db: &mut Database, method: &Method, url: &str, low_water: u32) -> (u16, Value) {
+    if *method != Method::Get {
+        return (405, json!({ "error": "Only GET is supported" }));
+    }
+
+    let (path, query) = split_url(url);
+    // Only the leading slash comes off, so `/address/` and `//address` don't
+    // hand out an address the way `/address` does
+    let segments: Vec<&str> = path.strip_prefix('/').unwrap_or(path).split('/').collect();
+
+    let result = match segments[..] {
+        ["address"] => {
+            let label = query_param(query, "label");
+            db.next_unused(label.as_deref())
+                .and_then(|entry| match entry {
+                    Some(entry) => Ok((200, entry_json(db, &entry)?)),
+                    None => Ok((503, json!({ "error": "No addresses left" }))),
+                })
+        }
+        ["address", id] => match id.parse() {
+            // Only show addresses that have been handed out, otherwise
+            // anyone could walk the ids and see the whole pool
+            Ok(id) => db.entry(id).and_then(|entry| match entry {
+                Some(entry) if entry.dispensed_at.is_some() => Ok((200, entry_json(db, &entry)?)),
+                _ => Ok((404, json!({ "error": "No such address" }))),
+            }),
+            Err(_) => Ok((400, json!({ "error": "Bad address id" }))),
+        },
+        ["health"] => db.stats().and_then(|stats| {
+            let level = db.pool_level(low_water)?;
+            let status = match (level.unused, level.low) {
+                (0, _) => "empty",
+                (_, true) => "low",
+                _ => "ok",
+            };
+            let mut body = json!({ "status": status, "stats": stats, "low_water": low_water });
+            if level.low {
+                body["refill"] = json!(refill_message(&db.filename, &level));
+            }
+            Ok((200, body))
+        }),
+        _ => Ok((404, json!({ "error": "Not found" }))),
+    };
+
+    result.unwrap_or_else(|error| (500, json!({ "error": error.to_string() })))
+}
+
+fn entry_json(db: &Database, entry: &Entry) -> Result<Value> {
+    let mut json = json!({
+        "id": entry.id,
+        "address": entry.address,
+        "signed_message": entry.message,
+        "uri": entry.uri,
+    });
+    // Merkle rows aren't signed one by one, so there's no signed statement to show
+    if entry.merkle_proof.is_none() {
+        json["statement"] = json!(entry.statement);
+    }
+
+    let batch = db.batch(entry.batch_id)?;
+    if let Some(valid_until) = batch.as_ref().and_then(|batch| batch.valid_until.as_ref()) {
+        json["valid_until"] = json!(valid_until);
+    }
+
+    // Everything a client needs to check the address against its batch's root
+    if let Some(proof) = &entry.merkle_proof {
+        let signed_root = batch.and_then(|batch| batch.signed_root);
+        json["derivation_index"] = json!(entry.derivation_index);
+        json["merkle_proof"] = json!(proof);
+        json["signed_root"] = json!(signed_root);
+    }
+
+    Ok(json)
+}
+
+/// Pull a value out of a query string, undoing the percent-encoding
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+    use tiny_http::{Method, Server};
+
+    use super::{dispenses, percent_decode, route, run};
+    use crate::{
+        alert::RefillAlert,
+        database::test_batch,
+        webhook::{mock_receiver, Webhook},
+        Database, Entry,
+    };
+
+    fn test_db(dir: &tempfile::TempDir) -> Result<Database> {
+        let db = Database::create(&dir.path().join("ours.db"))?;
+        let batch_id = db.new_batch(&test_batch())?;
+        db.insert(Entry::new(batch_id, 0, "tb1qfirst", "signed first"))?;
+        db.insert(Entry::new(batch_id, 1, "tb1qsecond", "signed second"))?;
+        Ok(db)
+    }
+
+    #[test]
+    fn routes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = test_db(&dir)?;
+
+        // An address nobody has been given yet stays hidden
+        assert_eq!(route(&mut db, &Method::Get, "/address/1", 0).0, 404);
+        // Only `/address` itself hands one out
+        assert_eq!(route(&mut db, &Method::Get, "/addressfoo", 0).0, 404);
+        assert_eq!(route(&mut db, &Method::Get, "//address", 0).0, 404);
+        assert_eq!(route(&mut db, &Method::Get, "/address/", 0).0, 400);
+        assert!(dispenses(&Method::Get, "/address?label=order%2042"));
+        assert!(!dispenses(&Method::Get, "/addressfoo"));
+        assert!(!dispenses(&Method::Get, "/address/1"));
+        assert!(!dispenses(&Method::Post, "/address"));
+
+        let (status, body) = route(&mut db, &Method::Get, "/address?label=order%2042", 0);
+        assert_eq!(status, 200);
+        assert_eq!(body["address"], "tb1qfirst");
+        assert_eq!(db.entry(1)?.unwrap().label.as_deref(), Some("order 42"));
+
+        let (status, body) = route(&mut db, &Method::Get, "/address/1", 0);
+        assert_eq!(status, 200);
+        assert_eq!(body["signed_message"], "signed first");
+        assert_eq!(
+            route(&mut db, &Method::Get, "/health", 5).1["status"],
+            "low"
+        );
+
+        assert_eq!(
+            route(&mut db, &Method::Get, "/address", 0).1["address"],
+            "tb1qsecond"
+        );
+        assert_eq!(route(&mut db, &Method::Get, "/address", 0).0, 503);
+
+        let (status, body) = route(&mut db, &Method::Get, "/health", 0);
+        assert_eq!(status, 200);
+        assert_eq!(body["stats"]["unused"], 0);
+        assert_eq!(body["status"], "empty");
+        assert!(body["refill"].as_str().unwrap().contains("--append"));
+
+        assert_eq!(route(&mut db, &Method::Post, "/address", 0).0, 405);
+        assert_eq!(route(&mut db, &Method::Get, "/nope", 0).0, 404);
+
+        Ok(())
+    }
+
+    #[test]
+    fn serves_over_http() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = test_db(&dir)?;
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr();
+        thread::spawn(move || run(server, db, RefillAlert::default()));
+
+        let mut stream = TcpStream::connect(address)?;
+        stream.write_all(b"GET /address HTTP/1.0\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        assert!(response.starts_with("HTTP/1.0 200"));
+        assert!(response.contains("tb1qfirst"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn alerts_without_holding_up_requests() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = test_db(&dir)?;
+
+        // A receiver that keeps failing would have the alert retrying for minutes
+        let (url, _received) = mock_receiver(usize::MAX);
+        let mut webhook = Webhook::new(&url);
+        webhook.backoff = Duration::from_secs(60);
+        let alert = RefillAlert {
+            low_water: 10,
+            webhook: Some(webhook),
+            file: None,
+        };
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr();
+        thread::spawn(move || run(server, db, alert));
+
+        let started = Instant::now();
+        for expected in &["tb1qfirst", "tb1qsecond"] {
+            let mut stream = TcpStream::connect(address)?;
+            stream.write_all(b"GET /address HTTP/1.0\r\n\r\n")?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            assert!(response.contains(expected));
+        }
+        assert!(started.elapsed() < Duration::from_secs(30));
+
+        Ok(())
+    }
+
+    #[test]
+    fn decodes_query_values() {
+        assert_eq!(percent_decode("order+%2342%21"), "order #42!");
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+}
@@ -0,0 +1,293 @@
+// Render a signed addresses database as a folder of static pages, for
+// anyone who can't run `serve`. There's no way to track which addresses
+// have been handed out on a static host, so the index just picks one at
+// random in the browser.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::Result;
+use serde_json::json;
+
+use crate::{
+    bip21::{payment_uri, PaymentRequest},
+    qr::{qr_svg, QrFormat},
+    Batch, Database,
+};
+
+pub struct SiteOptions {
+    pub title: String,
+    /// Label for the payment URIs, so wallets show who's being paid.
+    /// Only used for entries generated without a URI.
+    pub label: Option<String>,
+}
+
+/// Write the site into `out` and return how many addresses it holds.
+/// Addresses already handed out by `serve` or paid, or from batches past
+/// their validity window, are left out.
+pub fn export_site(db: &Database, out: &Path, options: &SiteOptions) -> Result<usize> {
+    let address_dir = out.join("address");
+    fs::create_dir_all(&address_dir)?;
+
+    let mut addresses = vec![];
+    let request = PaymentRequest {
+        label: options.label.clone(),
+        ..PaymentRequest::default()
+    };
+
+    let batches: HashMap<i64, Batch> = db
+        .batches()?
+        .into_iter()
+        .map(|batch| (batch.id, batch))
+        .collect();
+
+    for entry in db.entries()? {
+        let batch = batches.get(&entry.batch_id);
+        if entry.dispensed_at.is_some()
+            || entry.paid_at.is_some()
+            || batch.is_some_and(Batch::is_expired)
+        {
+            continue;
+        }
+
+        // Prefer the URI and QR code made at generation time when there are some
+        let uri = match &entry.uri {
+            Some(uri) => uri.clone(),
+            None => payment_uri(&entry.address, &request),
+        };
+        let qr_image = match db.qr_code(entry.id, QrFormat::Svg)? {
+            Some(image) if entry.uri.is_some() => image,
+            _ => qr_svg(&uri)?.into_bytes(),
+        };
+
+        // Merkle batches have one signed root instead, so show that with the proof
+        let signed_message = match &entry.merkle_proof {
+            Some(proof) => {
+                let signed_root = batch
+                    .and_then(|batch| batch.signed_root.as_deref())
+                    .unwrap_or_default();
+                format!(
+                    "{}\nIndex: {}\nMerkle proof: {}",
+                    signed_root, entry.derivation_index, proof
+                )
+            }
+            None => entry.message.clone(),
+        };
+
+        let page = format!("address/{}.html", entry.id);
+        let qr = format!("address/{}.svg", entry.id);
+
+        fs::write(out.join(&qr), qr_image)?;
+        fs::write(
+            out.join(&page),
+            address_page(
+                &options.title,
+                &entry.address,
+                &uri,
+                &signed_message,
+                entry.id,
+            ),
+        )?;
+
+        addresses.push(json!({
+            "id": entry.id,
+            "address": entry.address,
+            "uri": uri,
+            "signed_message": signed_message,
+            "page": page,
+            "qr": qr,
+        }));
+    }
+
+    let count = addresses.len();
+    fs::write(
+        out.join("addresses.json"),
+        serde_json::to_string_pretty(&addresses)?,
+    )?;
+    fs::write(out.join("index.html"), index_page(&options.title))?;
+
+    Ok(count)
+}
+
+const STYLE: &str =
+    "body { font-family: sans-serif; max-width: 40em; margin: 2em auto; padding: 0 1em; }
+img { display: block; width: 16em; height: 16em; }
+code { word-break: break-all; }
+pre { white-space: pre-wrap; word-break: break-all; background: #f4f4f4; padding: 1em; }";
+
+fn address_page(title: &str, address: &str, uri: &str, signed_message: &str, id: i32) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>{style}</style>
+</head>
+<body>
+<h1>{title}</h1>
+<a href="{uri}"><img src="{id}.svg" alt="QR code for {address}"></a>
+<p><a href="{uri}"><code>{address}</code></a></p>
+<p>This address is signed. You can check the signature with <code>gpg --verify</code>:</p>
+<pre>{signed_message}</pre>
+</body>
+</html>
+"#,
+        title = escape_html(title),
+        style = STYLE,
+        uri = escape_html(uri),
+        id = id,
+        address = escape_html(address),
+        signed_message = escape_html(signed_message),
+    )
+}
+
+fn index_page(title: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>{style}</style>
+</head>
+<body>
+<h1>{title}</h1>
+<div id="address"><noscript>Turn on JavaScript to get an address.</noscript></div>
+<script>
+// Pick a random address each visit so visitors don't all share one
+fetch("addresses.json")
+  .then(function (response) {{ return response.json(); }})
+  .then(function (addresses) {{
+    var container = document.getElementById("address");
+    if (addresses.length === 0) {{
+      container.textContent = "No addresses left.";
+      return;
+    }}
+    var pick = addresses[Math.floor(Math.random() * addresses.length)];
+
+    var link = document.createElement("a");
+    link.href = pick.uri;
+    var qr = document.createElement("img");
+    qr.src = pick.qr;
+    qr.alt = "QR code for " + pick.address;
+    link.appendChild(qr);
+    container.appendChild(link);
+
+    var address = document.createElement("p");
+    var code = document.createElement("code");
+    code.textContent = pick.address;
+    address.appendChild(code);
+    container.appendChild(address);
+
+    var signed = document.createElement("pre");
+    signed.textContent = pick.signed_message;
+    container.appendChild(signed);
+
+    var permalink = document.createElement("a");
+    permalink.href = pick.page;
+    permalink.textContent = "Link to this address";
+    container.appendChild(permalink);
+  }});
+</script>
+</body>
+</html>
+"#,
+        title = escape_html(title),
+        style = STYLE,
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+    use rusqlite::{params, Connection};
+    use serde_json::Value;
+
+    use super::{export_site, SiteOptions};
+    use crate::{database::test_batch, merkle::MerkleTree, Database, Entry};
+
+    #[test]
+    fn writes_a_page_per_unused_address() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = Database::create(&dir.path().join("ours.db"))?;
+        let mut batch = test_batch();
+        batch.count = 3;
+        let signed = db.new_batch(&batch)?;
+        db.insert(Entry::new(signed, 0, "tb1qdispensed", "signed dispensed"))?;
+        db.insert(Entry::new(
+            signed,
+            1,
+            "tb1qsigned",
+            "<script>alert(1)</script>",
+        ))?;
+        db.next_unused(None)?;
+        // Paid without being handed out by serve, say from an earlier copy of the site
+        db.insert(Entry::new(signed, 2, "tb1qpaid", "signed paid"))?;
+        Connection::open(dir.path().join("ours.db"))?.execute(
+            "UPDATE entries SET paid_at = '2021-03-20' WHERE address = 'tb1qpaid'",
+            params![],
+        )?;
+
+        batch.start_index = 3;
+        batch.count = 1;
+        batch.valid_until = Some("2000-01-01".to_string());
+        let expired = db.new_batch(&batch)?;
+        db.insert(Entry::new(expired, 3, "tb1qexpired", "signed expired"))?;
+
+        batch.start_index = 4;
+        batch.count = 2;
+        batch.valid_until = None;
+        batch.signed_root = Some("signed root".to_string());
+        let merkle = db.new_batch(&batch)?;
+        let tree = MerkleTree::new(vec![(4, "tb1qmerkle"), (5, "tb1qmerkle2")])?;
+        let proof = tree.proof(0).to_string();
+        let mut entry = Entry::new(merkle, 4, "tb1qmerkle", "");
+        entry.merkle_proof = Some(proof.clone());
+        db.insert(entry)?;
+
+        let out = dir.path().join("site");
+        let options = SiteOptions {
+            title: "Alice & Bob".to_string(),
+            label: Some("Alice".to_string()),
+        };
+        assert_eq!(export_site(&db, &out, &options)?, 2);
+
+        let addresses: Vec<Value> =
+            serde_json::from_str(&fs::read_to_string(out.join("addresses.json"))?)?;
+        let listed: Vec<&str> = addresses
+            .iter()
+            .map(|address| address["address"].as_str().unwrap())
+            .collect();
+        assert_eq!(listed, vec!["tb1qsigned", "tb1qmerkle"]);
+        assert_eq!(addresses[0]["uri"], "bitcoin:tb1qsigned?label=Alice");
+        assert_eq!(
+            addresses[1]["signed_message"],
+            format!("signed root\nIndex: 4\nMerkle proof: {}", proof)
+        );
+        assert!(proof.starts_with('R'));
+
+        assert!(fs::read_to_string(out.join("index.html"))?.contains("<h1>Alice &amp; Bob</h1>"));
+        for address in &addresses {
+            assert!(out.join(address["qr"].as_str().unwrap()).is_file());
+        }
+        let page = fs::read_to_string(out.join(addresses[0]["page"].as_str().unwrap()))?;
+        assert!(page.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!page.contains("<script>"));
+        let page = fs::read_to_string(out.join(addresses[1]["page"].as_str().unwrap()))?;
+        assert!(page.contains(&format!("Merkle proof: {}", proof)));
+
+        Ok(())
+    }
+}
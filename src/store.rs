@@ -0,0 +1,393 @@
+// Where generated addresses end up. SQLite is the default, but a production
+// server might run Postgres or MySQL, so we can also write a SQL file of
+// plain INSERT statements to load there.
+
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    manifest::{BatchSummary, Manifest},
+    qr::QrFormat,
+    Batch, Database, Entry,
+};
+
+pub trait Store {
+    /// Record a new batch and return its id for the entries that belong to it
+    fn create_batch(&mut self, batch: &Batch) -> Result<i64>;
+
+    /// Store an entry and return its id
+    fn insert_entry(&mut self, entry: Entry) -> Result<i32>;
+
+    fn insert_qr_code(&mut self, entry_id: i32, format: QrFormat, image: &[u8]) -> Result<()>;
+
+    /// Make everything inserted so far stick, `stored` being how many entries
+    /// of the batch that is. Writes in between are grouped into one transaction.
+    fn checkpoint(&mut self, batch_id: i64, stored: u32) -> Result<()>;
+
+    /// Called once everything has been inserted
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Summarise what's been written, once it's all been written
+    fn manifest(&mut self) -> Result<Manifest>;
+
+    /// Where the addresses went, for telling the user
+    fn location(&self) -> String;
+}
+
+impl Store for Database {
+    fn create_batch(&mut self, batch: &Batch) -> Result<i64> {
+        self.check_batch_fits(batch)?;
+        // The batch registry hashes the file, so keep everything committed in it
+        self.flush_wal()?;
+        self.begin()?;
+        self.new_batch(batch)
+    }
+
+    fn insert_entry(&mut self, entry: Entry) -> Result<i32> {
+        self.begin()?;
+        self.insert(entry)
+    }
+
+    fn insert_qr_code(&mut self, entry_id: i32, format: QrFormat, image: &[u8]) -> Result<()> {
+        self.begin()?;
+        Database::insert_qr_code(self, entry_id, format, image)
+    }
+
+    fn checkpoint(&mut self, batch_id: i64, stored: u32) -> Result<()> {
+        self.begin()?;
+        self.set_batch_count(batch_id, stored)?;
+        self.commit()?;
+        self.flush_wal()
+    }
+
+    fn manifest(&mut self) -> Result<Manifest> {
+        self.flush_wal()?;
+
+        let batches = self.batches()?;
+        let mut summaries = vec![];
+        for batch in &batches {
+            if let Some((first, last)) = self.first_and_last_address(batch.id)? {
+                summaries.push(BatchSummary::new(batch, &first, &last));
+            }
+        }
+
+        match batches.first() {
+            Some(first) => Manifest::new(Path::new(&self.filename), summaries, first),
+            None => bail!("{} has no batches to summarise", self.filename),
+        }
+    }
+
+    fn location(&self) -> String {
+        self.filename.clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SqlDialect {
+    Postgres,
+    MySql,
+}
+
+impl FromStr for SqlDialect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "postgres" | "postgresql" => Ok(SqlDialect::Postgres),
+            "mysql" | "mariadb" => Ok(SqlDialect::MySql),
+            _ => bail!("Unknown SQL dialect {}, expected postgres or mysql", s),
+        }
+    }
+}
+
+/// Writes a `.sql` file that creates the same tables as the SQLite database
+/// (if they don't exist yet) and inserts the batch a transaction per checkpoint.
+/// Rows refer to each other by natural keys instead of ids, since the ids
+/// are picked by the database it's loaded into.
+pub struct SqlDump {
+    writer: BufWriter<std::fs::File>,
+    dialect: SqlDialect,
+    filename: String,
+    /// Entries look their batch up by its descriptor checksum and start index
+    batch: Option<Batch>,
+    /// Entries get their ids when loaded, so keep their addresses for the QR codes
+    addresses: Vec<String>,
+    in_transaction: bool,
+}
+
+impl SqlDump {
+    pub fn create(path: &Path, dialect: SqlDialect) -> Result<Self> {
+        if path.exists() {
+            bail!("{} already exists", path.to_string_lossy())
+        }
+
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut dump = Self {
+            writer: BufWriter::new(file),
+            dialect,
+            filename: path.to_string_lossy().to_string(),
+            batch: None,
+            addresses: vec![],
+            in_transaction: false,
+        };
+
+        writeln!(
+            dump.writer,
+            "-- Generated by Address Factory {}",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(dump.writer, "{}", dump.schema())?;
+
+        Ok(dump)
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        if !self.in_transaction {
+            match self.dialect {
+                SqlDialect::Postgres => writeln!(self.writer, "BEGIN;")?,
+                SqlDialect::MySql => writeln!(self.writer, "START TRANSACTION;")?,
+            }
+            self.in_transaction = true;
+        }
+        Ok(())
+    }
+
+    fn schema(&self) -> String {
+        let (id, blob) = match self.dialect {
+            SqlDialect::Postgres => ("BIGSERIAL PRIMARY KEY", "BYTEA"),
+            SqlDialect::MySql => ("BIGINT AUTO_INCREMENT PRIMARY KEY", "LONGBLOB"),
+        };
+        // MySQL can only index a bounded prefix of TEXT, so use VARCHAR for keys
+        format!(
+            "CREATE TABLE IF NOT EXISTS batches (
+    id                      {id},
+    descriptor_checksum     VARCHAR(16) NOT NULL,
+    network                 VARCHAR(16) NOT NULL,
+    start_index             BIGINT NOT NULL,
+    count                   BIGINT NOT NULL,
+    signing_key_fingerprint VARCHAR(64) NOT NULL,
+    created_at              VARCHAR(64) NOT NULL,
+    tool_version            VARCHAR(32) NOT NULL,
+    merkle_root             VARCHAR(64),
+    signed_root             TEXT,
+    valid_until             VARCHAR(10),
+    UNIQUE(descriptor_checksum, start_index)
+);
+CREATE TABLE IF NOT EXISTS entries (
+    id               {id},
+    batch_id         BIGINT NOT NULL REFERENCES batches(id),
+    derivation_index BIGINT NOT NULL,
+    address          VARCHAR(128) NOT NULL UNIQUE,
+    message          TEXT NOT NULL,
+    dispensed_at     VARCHAR(64),
+    label            TEXT,
+    paid_at          VARCHAR(64),
+    uri              TEXT,
+    merkle_proof     TEXT,
+    statement        TEXT,
+    UNIQUE(batch_id, derivation_index)
+);
+CREATE TABLE IF NOT EXISTS qr_codes (
+    entry_id         BIGINT NOT NULL REFERENCES entries(id),
+    format           VARCHAR(8) NOT NULL,
+    image            {blob} NOT NULL,
+    PRIMARY KEY(entry_id, format)
+);",
+            id = id,
+            blob = blob
+        )
+    }
+
+    /// A string literal safe to paste into the dump
+    fn quote(&self, value: &str) -> String {
+        let value = value.replace('\'', "''");
+        match self.dialect {
+            SqlDialect::Postgres => format!("'{}'", value),
+            // MySQL treats backslashes as escapes by default
+            SqlDialect::MySql => format!("'{}'", value.replace('\\', "\\\\")),
+        }
+    }
+
+    fn quote_optional(&self, value: &Option<String>) -> String {
+        match value {
+            Some(value) => self.quote(value),
+            None => "NULL".to_string(),
+        }
+    }
+
+    fn blob(&self, bytes: &[u8]) -> String {
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        match self.dialect {
+            SqlDialect::Postgres => format!("decode('{}', 'hex')", hex),
+            SqlDialect::MySql => format!("X'{}'", hex),
+        }
+    }
+}
+
+impl Store for SqlDump {
+    fn create_batch(&mut self, batch: &Batch) -> Result<i64> {
+        let statement = format!(
+            "INSERT INTO batches (descriptor_checksum, network, start_index, count, \
+             signing_key_fingerprint, created_at, tool_version, merkle_root, signed_root, \
+             valid_until) \
+             VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {}, {});",
+            self.quote(&batch.descriptor_checksum),
+            self.quote(&batch.network),
+            batch.start_index,
+            batch.count,
+            self.quote(&batch.signing_key_fingerprint),
+            self.quote(&batch.created_at),
+            self.quote(&batch.tool_version),
+            self.quote_optional(&batch.merkle_root),
+            self.quote_optional(&batch.signed_root),
+            self.quote_optional(&batch.valid_until),
+        );
+        self.begin()?;
+        writeln!(self.writer, "{}", statement)?;
+
+        self.batch = Some(batch.clone());
+        Ok(0)
+    }
+
+    fn insert_entry(&mut self, entry: Entry) -> Result<i32> {
+        let (checksum, start_index) = match &self.batch {
+            Some(batch) => (batch.descriptor_checksum.clone(), batch.start_index),
+            None => bail!("Can't insert an entry before its batch"),
+        };
+
+        let statement = format!(
+            "INSERT INTO entries (batch_id, derivation_index, address, message, dispensed_at, \
+             label, paid_at, uri, merkle_proof, statement) \
+             SELECT id, {}, {}, {}, {}, {}, {}, {}, {}, {} FROM batches \
+             WHERE descriptor_checksum = {} AND start_index = {};",
+            entry.derivation_index,
+            self.quote(&entry.address),
+            self.quote(&entry.message),
+            self.quote_optional(&entry.dispensed_at),
+            self.quote_optional(&entry.label),
+            self.quote_optional(&entry.paid_at),
+            self.quote_optional(&entry.uri),
+            self.quote_optional(&entry.merkle_proof),
+            self.quote_optional(&entry.statement),
+            self.quote(&checksum),
+            start_index,
+        );
+        self.begin()?;
+        writeln!(self.writer, "{}", statement)?;
+
+        self.addresses.push(entry.address);
+        Ok(self.addresses.len() as i32)
+    }
+
+    fn insert_qr_code(&mut self, entry_id: i32, format: QrFormat, image: &[u8]) -> Result<()> {
+        let address = match self.addresses.get(entry_id as usize - 1) {
+            Some(address) => address.clone(),
+            None => bail!("No entry {} in this dump", entry_id),
+        };
+
+        let statement = format!(
+            "INSERT INTO qr_codes (entry_id, format, image) \
+             SELECT id, {}, {} FROM entries WHERE address = {};",
+            self.quote(format.extension()),
+            self.blob(image),
+            self.quote(&address),
+        );
+        self.begin()?;
+        writeln!(self.writer, "{}", statement)?;
+
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, _batch_id: i64, stored: u32) -> Result<()> {
+        let (checksum, start_index) = match &self.batch {
+            Some(batch) => (batch.descriptor_checksum.clone(), batch.start_index),
+            None => bail!("Can't checkpoint before a batch"),
+        };
+
+        self.begin()?;
+        writeln!(
+            self.writer,
+            "UPDATE batches SET count = {} WHERE descriptor_checksum = {} AND start_index = {};",
+            stored,
+            self.quote(&checksum),
+            start_index
+        )?;
+        writeln!(self.writer, "COMMIT;")?;
+        self.in_transaction = false;
+
+        // A dump cut short should still end on a whole transaction
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.in_transaction {
+            writeln!(self.writer, "COMMIT;")?;
+            self.in_transaction = false;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn manifest(&mut self) -> Result<Manifest> {
+        self.writer.flush()?;
+
+        let batch = match &self.batch {
+            Some(batch) => batch,
+            None => bail!("{} has no batch to summarise", self.filename),
+        };
+        let mut summaries = vec![];
+        if let (Some(first), Some(last)) = (self.addresses.first(), self.addresses.last()) {
+            summaries.push(BatchSummary::new(batch, first, last));
+        }
+
+        Manifest::new(Path::new(&self.filename), summaries, batch)
+    }
+
+    fn location(&self) -> String {
+        self.filename.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+
+    use super::{SqlDialect, SqlDump, Store};
+    use crate::{database::test_batch, qr::QrFormat, Entry};
+
+    #[test]
+    fn writes_sql_dump() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("batch.sql");
+
+        let mut dump = SqlDump::create(&path, SqlDialect::MySql)?;
+        let batch_id = dump.create_batch(&test_batch())?;
+        let entry_id = dump.insert_entry(Entry::new(batch_id, 0, "tb1qfirst", "it's \\signed"))?;
+        dump.insert_qr_code(entry_id, QrFormat::Png, &[0xde, 0xad])?;
+        dump.checkpoint(batch_id, 1)?;
+        dump.finish()?;
+
+        let sql = fs::read_to_string(&path)?;
+        assert!(sql.contains("CREATE TABLE IF NOT EXISTS entries"));
+        assert!(sql.contains("'it''s \\\\signed'"));
+        assert!(sql.contains("WHERE descriptor_checksum = 'pjd3nkdg' AND start_index = 0;"));
+        assert!(sql.contains("X'dead' FROM entries WHERE address = 'tb1qfirst';"));
+        assert!(sql.contains("START TRANSACTION;"));
+        assert!(sql.trim_end().ends_with("start_index = 0;\nCOMMIT;"));
+
+        assert!(SqlDump::create(&path, SqlDialect::Postgres).is_err());
+
+        Ok(())
+    }
+}
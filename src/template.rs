@@ -0,0 +1,171 @@
+// The factory's message is a template for the text signed with each address,
+// e.g. "Donation address #{index} for Alice, valid from {date}: {address}".
+// A message without {address} gets the address on the end, the way plain
+// messages have always been signed, and a batch with a validity window gets a
+// "Valid until:" line unless the message says where it goes.
+
+use anyhow::{bail, Result};
+
+/// Every placeholder a template can use
+pub const PLACEHOLDERS: &[&str] = &[
+    "address",
+    "index",
+    "date",
+    "batch",
+    "network",
+    "uri",
+    "valid_until",
+];
+
+/// Line a validity window is added on, in address statements and Merkle roots
+pub const VALID_UNTIL: &str = "Valid until: ";
+
+/// What the placeholders stand for, for one address
+#[derive(Debug)]
+pub struct Values<'a> {
+    pub address: &'a str,
+    pub index: u32,
+    /// Day the batch was made, YYYY-MM-DD
+    pub date: &'a str,
+    /// Which of the factory's batches this is, counting from 1
+    pub batch: usize,
+    pub network: &'a str,
+    pub uri: Option<&'a str>,
+    /// Last day to hand the address out, YYYY-MM-DD
+    pub valid_until: Option<&'a str>,
+}
+
+/// Make sure the template only uses placeholders we can fill in
+pub fn check(template: &str, with_uri: bool, with_valid_until: bool) -> Result<()> {
+    for name in placeholders(template) {
+        if !PLACEHOLDERS.contains(&name) {
+            bail!(
+                "The message uses {{{}}}, but the placeholders are {{{}}}",
+                name,
+                PLACEHOLDERS.join("}, {")
+            )
+        }
+        if name == "uri" && !with_uri {
+            bail!("The message uses {{uri}}, so run with --uri")
+        }
+        if name == "valid_until" && !with_valid_until {
+            bail!("The message uses {{valid_until}}, so run with --valid-until")
+        }
+    }
+    Ok(())
+}
+
+/// The text to sign for one address, which always includes the address
+/// and the validity window if there is one
+pub fn statement(template: &str, values: &Values) -> String {
+    let mut text = render(template, values);
+    if !placeholders(template).any(|name| name == "address") {
+        text = format!("{} {}", text, values.address);
+    }
+    if let Some(valid_until) = values.valid_until {
+        if !placeholders(template).any(|name| name == "valid_until") {
+            text = format!("{}\n{}{}", text, VALID_UNTIL, valid_until);
+        }
+    }
+    text
+}
+
+/// The validity window in a signed statement, if it has a "Valid until:" line
+pub fn signed_valid_until(statement: &str) -> Option<&str> {
+    statement
+        .lines()
+        .find_map(|line| line.strip_prefix(VALID_UNTIL))
+        .map(str::trim)
+}
+
+/// Fill in the placeholders
+pub fn render(template: &str, values: &Values) -> String {
+    let mut text = template.to_string();
+    for name in PLACEHOLDERS {
+        let value = match *name {
+            "address" => values.address.to_string(),
+            "index" => values.index.to_string(),
+            "date" => values.date.to_string(),
+            "batch" => values.batch.to_string(),
+            "network" => values.network.to_string(),
+            "uri" => values.uri.unwrap_or_default().to_string(),
+            _ => values.valid_until.unwrap_or_default().to_string(),
+        };
+        text = text.replace(&format!("{{{}}}", name), &value);
+    }
+    text
+}
+
+/// Names of the `{word}`s in the template. Braces around anything else are left alone.
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template.split('{').skip(1).filter_map(|rest| {
+        let name = rest.split('}').next()?;
+        let is_word = rest.contains('}')
+            && !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_lowercase() || c == '_');
+        if is_word {
+            Some(name)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, render, signed_valid_until, statement, Values};
+
+    #[test]
+    fn renders_templates() {
+        let values = Values {
+            address: "tb1qexample",
+            index: 42,
+            date: "2021-03-20",
+            batch: 3,
+            network: "testnet",
+            uri: Some("bitcoin:tb1qexample?label=Alice"),
+            valid_until: None,
+        };
+
+        assert_eq!(
+            statement(
+                "Donation address #{index} for Alice, valid from {date}: {address}",
+                &values
+            ),
+            "Donation address #42 for Alice, valid from 2021-03-20: tb1qexample"
+        );
+        // Plain messages are signed the way they always were
+        assert_eq!(
+            statement("Donate to Alice", &values),
+            "Donate to Alice tb1qexample"
+        );
+        assert_eq!(render("Donate to Alice", &values), "Donate to Alice");
+        assert_eq!(
+            statement("Batch {batch} on {network} {uri} {\"json\": 1}", &values),
+            "Batch 3 on testnet bitcoin:tb1qexample?label=Alice {\"json\": 1} tb1qexample"
+        );
+
+        assert!(check("#{index}: {address}", false, false).is_ok());
+        assert!(check("{\"json\": 1}", false, false).is_ok());
+        assert!(check("{adress}", false, false).is_err());
+        assert!(check("{uri}", false, false).is_err());
+        assert!(check("{uri}", true, false).is_ok());
+        assert!(check("until {valid_until}", false, false).is_err());
+        assert!(check("until {valid_until}", false, true).is_ok());
+
+        let values = Values {
+            valid_until: Some("2027-06-30"),
+            ..values
+        };
+        let signed = statement("Donate to Alice", &values);
+        assert_eq!(
+            signed,
+            "Donate to Alice tb1qexample\nValid until: 2027-06-30"
+        );
+        assert_eq!(signed_valid_until(&signed), Some("2027-06-30"));
+        assert_eq!(
+            statement("{address} is good until {valid_until}", &values),
+            "tb1qexample is good until 2027-06-30"
+        );
+    }
+}
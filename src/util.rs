@@ -4,13 +4,12 @@ use bdk::{
         util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey},
         Address, Network,
     },
-    database::MemoryDatabase,
     descriptor::Descriptor,
     miniscript::DescriptorPublicKey,
-    wallet::AddressIndex,
-    Wallet,
 };
 
+use crate::derive::Deriver;
+
 pub type Desc = Descriptor<DescriptorPublicKey>;
 
 pub enum ScriptType {
@@ -24,10 +23,7 @@ pub fn nth_address(
     network: Network,
     index: u32,
 ) -> Result<Address> {
-    let wallet = Wallet::new_offline(descriptor, None, network, MemoryDatabase::default())?;
-    let address = wallet.get_address(AddressIndex::Peek(index))?;
-
-    Ok(address)
+    Deriver::new(&descriptor, network)?.address(index)
 }
 
 /// Check that first address derived matches given address
@@ -46,6 +42,15 @@ pub fn check_address(
     }
 }
 
+/// The part of the descriptor string after the #
+pub fn descriptor_checksum(descriptor: &Desc) -> String {
+    let descriptor = descriptor.to_string();
+    match descriptor.split_once('#') {
+        Some((_, checksum)) => checksum.to_string(),
+        None => descriptor,
+    }
+}
+
 pub fn script_type(path: &DerivationPath) -> Result<ScriptType> {
     let version_number = path.into_iter().next().context("No path")?;
 
@@ -102,7 +107,7 @@ mod tests {
 
     fn test_vector(path: &str, extended_public_key: &str, first_address: &str) -> Result<()> {
         let xpub =
-            ExtendedPubKey::from_slip132_str(&extended_public_key).expect("Failed to make an xpub");
+            ExtendedPubKey::from_slip132_str(extended_public_key).expect("Failed to make an xpub");
         let derivation_path = bitcoin::util::bip32::DerivationPath::from_str(path)
             .expect("Failed to make a derivation path");
 
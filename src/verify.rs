@@ -0,0 +1,1150 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::Range,
+};
+
+use anyhow::{bail, Result};
+use bdk::bitcoin::{hashes::sha256, Network};
+
+use crate::{
+    bip21::uri_address,
+    derive::Deriver,
+    gpg::gpg_verify,
+    history::BatchRecord,
+    merkle::{root_from_statement, statement_field, Proof},
+    qr::{qr_code, QrFormat},
+    template::{self, Values},
+    util, Batch, Database, Desc, Entry, Keyring,
+};
+
+/// How many missing indexes to list for a batch before just counting the rest
+const MAX_LISTED_MISSING: usize = 100;
+
+/// Something wrong with a row in a signed address database
+#[derive(Debug)]
+pub enum Problem {
+    /// The address isn't the one the descriptor gives for this row's index
+    AddressMismatch {
+        id: i32,
+        index: u32,
+        expected: String,
+        found: String,
+        derived_at: Option<u32>,
+    },
+    /// The signature doesn't check out against the public key
+    BadSignature { id: i32, address: String },
+    /// The signature is fine but it signs something other than the rendered message
+    SignedTextMismatch {
+        id: i32,
+        expected: String,
+        found: String,
+    },
+    /// The signature is fine but the signed text isn't for this row's address
+    AddressNotSigned {
+        id: i32,
+        address: String,
+        found: String,
+    },
+    /// The statement stored with the row isn't the text that was signed
+    StatementMismatch {
+        id: i32,
+        signed: String,
+        stored: String,
+    },
+    /// The batch was made from a different descriptor than the factory's
+    DescriptorMismatch {
+        batch: i64,
+        expected: String,
+        found: String,
+    },
+    /// The batch was made for a different network than the factory's
+    NetworkMismatch {
+        batch: i64,
+        expected: String,
+        found: String,
+    },
+    /// No row for an index inside the range a batch covers
+    MissingIndex { batch: i64, index: u32 },
+    /// Missing rows past the ones listed, too many for the batch's count to be right
+    ManyMissing { batch: i64, more: usize },
+    /// The batch's range runs past the last index a wallet can derive
+    InvalidRange {
+        batch: i64,
+        start_index: u32,
+        count: u32,
+    },
+    /// A row whose index is outside the range its batch covers
+    OutOfRange { id: i32, batch: i64, index: u32 },
+    /// The same address shows up in more than one row
+    Duplicate { address: String, ids: Vec<i32> },
+    /// The batch's signed Merkle root doesn't check out against the public key
+    BadRootSignature { batch: i64 },
+    /// The signed statement commits to a different root than the one stored
+    RootMismatch {
+        batch: i64,
+        expected: String,
+        found: String,
+    },
+    /// The signed root statement describes a different batch than the stored one
+    RootStatementMismatch {
+        batch: i64,
+        field: &'static str,
+        signed: Option<String>,
+        stored: String,
+    },
+    /// The row's Merkle proof doesn't lead from its address to the signed root
+    BadProof { id: i32, address: String },
+    /// A row in a Merkle batch carries a statement, which nothing signs
+    UnsignedStatement { id: i32 },
+    /// The row's payment URI pays some other address
+    UriMismatch {
+        id: i32,
+        address: String,
+        uri: String,
+    },
+    /// The row's stored QR code isn't its payment URI
+    QrMismatch { id: i32, format: QrFormat },
+    /// The batch's valid-until date isn't the one its addresses were signed with
+    ValidUntilMismatch {
+        batch: i64,
+        signed: Option<String>,
+        stored: Option<String>,
+    },
+}
+
+/// Something that doesn't make a database invalid but should be dealt with
+#[derive(Debug)]
+pub enum Warning {
+    /// The batch's validity window has passed, so its addresses shouldn't be handed out
+    Expired {
+        batch: i64,
+        valid_until: String,
+        unused: usize,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::Expired {
+                batch,
+                valid_until,
+                unused,
+            } => write!(
+                f,
+                "Batch {}: expired after {}, {} of its addresses were never handed out",
+                batch, valid_until, unused
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::AddressMismatch {
+                id,
+                index,
+                expected,
+                found,
+                derived_at,
+            } => {
+                write!(
+                    f,
+                    "Row {}: expected {} at index {} but found {}",
+                    id, expected, index, found
+                )?;
+                match derived_at {
+                    Some(other) => write!(f, " (that's index {})", other),
+                    None => write!(f, " (not derived from this descriptor)"),
+                }
+            }
+            Problem::BadSignature { id, address } => {
+                write!(f, "Row {}: bad signature for {}", id, address)
+            }
+            Problem::SignedTextMismatch {
+                id,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Row {}: signed text is \"{}\" but expected \"{}\"",
+                id, found, expected
+            ),
+            Problem::AddressNotSigned { id, address, found } => write!(
+                f,
+                "Row {}: signed text \"{}\" doesn't include its address {}",
+                id, found, address
+            ),
+            Problem::StatementMismatch { id, signed, stored } => write!(
+                f,
+                "Row {}: stored statement is \"{}\" but the signed text is \"{}\"",
+                id, stored, signed
+            ),
+            Problem::DescriptorMismatch {
+                batch,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Batch {}: descriptor checksum is {} but expected {}",
+                batch, found, expected
+            ),
+            Problem::NetworkMismatch {
+                batch,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Batch {}: network is {} but expected {}",
+                batch, found, expected
+            ),
+            Problem::MissingIndex { batch, index } => {
+                write!(f, "Batch {}: no row for index {}", batch, index)
+            }
+            Problem::ManyMissing { batch, more } => write!(
+                f,
+                "Batch {}: no rows for {} more indexes, has its count been edited?",
+                batch, more
+            ),
+            Problem::InvalidRange {
+                batch,
+                start_index,
+                count,
+            } => write!(
+                f,
+                "Batch {}: {} indexes from {} runs past the last index a wallet can derive",
+                batch, count, start_index
+            ),
+            Problem::OutOfRange { id, batch, index } => write!(
+                f,
+                "Row {}: index {} is outside the range of batch {}",
+                id, index, batch
+            ),
+            Problem::Duplicate { address, ids } => {
+                write!(f, "{} appears in rows {:?}", address, ids)
+            }
+            Problem::BadRootSignature { batch } => {
+                write!(f, "Batch {}: bad signature on the Merkle root", batch)
+            }
+            Problem::RootMismatch {
+                batch,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Batch {}: signed Merkle root is {} but the batch has {}",
+                batch, found, expected
+            ),
+            Problem::RootStatementMismatch {
+                batch,
+                field,
+                signed,
+                stored,
+            } => write!(
+                f,
+                "Batch {}: signed root has {} {} but the batch has {}",
+                batch,
+                field,
+                signed.as_deref().unwrap_or("missing"),
+                stored
+            ),
+            Problem::UnsignedStatement { id } => write!(
+                f,
+                "Row {}: has a statement, but its batch only signs the Merkle root",
+                id
+            ),
+            Problem::BadProof { id, address } => {
+                write!(
+                    f,
+                    "Row {}: Merkle proof for {} doesn't match the root",
+                    id, address
+                )
+            }
+            Problem::UriMismatch { id, address, uri } => write!(
+                f,
+                "Row {}: payment URI {} doesn't pay its address {}",
+                id, uri, address
+            ),
+            Problem::QrMismatch { id, format } => write!(
+                f,
+                "Row {}: {} QR code doesn't encode its payment URI",
+                id,
+                format.extension()
+            ),
+            Problem::ValidUntilMismatch {
+                batch,
+                signed,
+                stored,
+            } => write!(
+                f,
+                "Batch {}: valid until {} but its addresses were signed valid until {}",
+                batch,
+                stored.as_deref().unwrap_or("forever"),
+                signed.as_deref().unwrap_or("forever")
+            ),
+        }
+    }
+}
+
+/// Outcome of checking every row in a database
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checked: usize,
+    pub problems: Vec<Problem>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "Warning: {}", warning)?;
+        }
+        write!(
+            f,
+            "Checked {} entries, found {} problems",
+            self.checked,
+            self.problems.len()
+        )
+    }
+}
+
+/// Re-derive every address in the database and check it along with its signature.
+/// `history` is the factory's batch registry, which holds the message each batch was
+/// signed with and numbers batches for `{batch}`. `message` is for batches it has no record of.
+pub fn verify_database(
+    db: &Database,
+    descriptor: Desc,
+    network: Network,
+    message: &str,
+    history: &[BatchRecord],
+    keyring: &Keyring,
+) -> Result<Report> {
+    let batches = db.batches()?;
+    let entries = db.entries()?;
+    let mut report = Report::default();
+
+    let checksum = util::descriptor_checksum(&descriptor);
+    let deriver = Deriver::new(&descriptor, network)?;
+    let roots = check_roots(&batches, keyring, &mut report.problems);
+    // Where to look when an address turns up at the wrong index. Batches whose
+    // range can't be right are left out rather than derived in full.
+    let mut search_ranges = vec![];
+
+    for batch in &batches {
+        if batch.descriptor_checksum != checksum {
+            report.problems.push(Problem::DescriptorMismatch {
+                batch: batch.id,
+                expected: checksum.clone(),
+                found: batch.descriptor_checksum.clone(),
+            });
+        }
+        if batch.network != network.to_string() {
+            report.problems.push(Problem::NetworkMismatch {
+                batch: batch.id,
+                expected: network.to_string(),
+                found: batch.network.clone(),
+            });
+        }
+
+        let range = match batch.end_index() {
+            Some(end) => batch.start_index..end,
+            None => {
+                report.problems.push(Problem::InvalidRange {
+                    batch: batch.id,
+                    start_index: batch.start_index,
+                    count: batch.count,
+                });
+                continue;
+            }
+        };
+        let indexes: HashSet<u32> = entries
+            .iter()
+            .filter(|entry| entry.batch_id == batch.id && range.contains(&entry.derivation_index))
+            .map(|entry| entry.derivation_index)
+            .collect();
+        let missing = batch.count as usize - indexes.len();
+        // An edited count could claim billions of indexes, so only list the first few
+        let listed = range
+            .clone()
+            .filter(|index| !indexes.contains(index))
+            .take(MAX_LISTED_MISSING);
+        for index in listed {
+            report.problems.push(Problem::MissingIndex {
+                batch: batch.id,
+                index,
+            });
+        }
+        if missing > MAX_LISTED_MISSING {
+            report.problems.push(Problem::ManyMissing {
+                batch: batch.id,
+                more: missing - MAX_LISTED_MISSING,
+            });
+        } else {
+            search_ranges.push(range);
+        }
+    }
+
+    let mut derived_indexes = None;
+
+    for entry in &entries {
+        let index = entry.derivation_index;
+
+        let in_range = batches.iter().any(|batch| {
+            batch.id == entry.batch_id
+                && batch
+                    .end_index()
+                    .is_some_and(|end| (batch.start_index..end).contains(&index))
+        });
+        if !in_range {
+            report.problems.push(Problem::OutOfRange {
+                id: entry.id,
+                batch: entry.batch_id,
+                index,
+            });
+        }
+
+        let expected = deriver.address(index)?.to_string();
+        if entry.address != expected {
+            report.problems.push(Problem::AddressMismatch {
+                id: entry.id,
+                index,
+                expected,
+                found: entry.address.clone(),
+                derived_at: find_index(
+                    &mut derived_indexes,
+                    &deriver,
+                    &entry.address,
+                    &search_ranges,
+                )?,
+            });
+        }
+
+        let expected = match batches.iter().find(|batch| batch.id == entry.batch_id) {
+            Some(batch) => {
+                let values = Values {
+                    address: &entry.address,
+                    index,
+                    date: batch.created_at.get(..10).unwrap_or_default(),
+                    // Batches are numbered in the order they were made, which is index order.
+                    // Runs that stopped before storing anything don't count.
+                    batch: history
+                        .iter()
+                        .filter(|record| record.count > 0 && record.start_index < batch.start_index)
+                        .count()
+                        + 1,
+                    network: &batch.network,
+                    uri: entry.uri.as_deref(),
+                    valid_until: batch.valid_until.as_deref(),
+                };
+                // The message may have been edited since, so use the one this batch was made with
+                let template = history
+                    .iter()
+                    .find(|record| record.count > 0 && record.start_index == batch.start_index)
+                    .map_or(message, |record| record.message.as_str());
+                Some(template::statement(template, &values))
+            }
+            // Already reported as out of range
+            None => None,
+        };
+        check_entry(
+            entry,
+            &roots,
+            expected.as_deref(),
+            keyring,
+            &mut report.problems,
+        );
+        check_payment_links(db, entry, &mut report.problems)?;
+        report.checked += 1;
+    }
+
+    report.problems.append(&mut find_duplicates(&entries));
+    report.warnings = expired_batches(&batches, &entries);
+
+    Ok(report)
+}
+
+/// Check every signature in the database using nothing but the signer's public key.
+/// This is meant to run on the server, where the xpub isn't available, so it can't
+/// tell whether an address belongs to the wallet. It can tell whether the signed
+/// statement is for the address in the same row, which catches a swapped address.
+pub fn verify_signatures(db: &Database, keyring: &Keyring) -> Result<Report> {
+    let batches = db.batches()?;
+    let entries = db.entries()?;
+    let mut report = Report::default();
+    let roots = check_roots(&batches, keyring, &mut report.problems);
+    // Every address in a batch is signed with the same window, so one row each will do
+    let mut windows_checked = HashSet::new();
+
+    for entry in &entries {
+        let signed_text = check_entry(entry, &roots, None, keyring, &mut report.problems);
+        let batch = batches.iter().find(|batch| batch.id == entry.batch_id);
+        if let (Some(signed_text), Some(batch)) = (signed_text, batch) {
+            if windows_checked.insert(batch.id) {
+                check_valid_until(batch, &signed_text, &mut report.problems);
+            }
+        }
+        check_payment_links(db, entry, &mut report.problems)?;
+        report.checked += 1;
+    }
+
+    report.problems.append(&mut find_duplicates(&entries));
+    report.warnings = expired_batches(&batches, &entries);
+
+    Ok(report)
+}
+
+/// Check a single address against its batch's signed Merkle root, the way a
+/// client that was handed an address, its index and proof would.
+/// Returns the signed statement so it can be shown.
+pub fn verify_address_proof(
+    address: &str,
+    index: u32,
+    proof: &str,
+    signed_root: &str,
+    keyring: &Keyring,
+) -> Result<String> {
+    let statement = match gpg_verify(signed_root, keyring) {
+        Ok(statement) => statement,
+        Err(_) => bail!("Bad signature on the Merkle root"),
+    };
+    let root = root_from_statement(&statement)?;
+
+    if !proof.parse::<Proof>()?.verify(index, address, &root) {
+        bail!("{} at index {} isn't under the signed root", address, index)
+    }
+
+    Ok(statement)
+}
+
+/// Check the signed root of every Merkle batch. The map has an entry for each
+/// Merkle batch, holding its signed root if the signature checks out.
+fn check_roots(
+    batches: &[Batch],
+    keyring: &Keyring,
+    problems: &mut Vec<Problem>,
+) -> HashMap<i64, Option<sha256::Hash>> {
+    let mut roots = HashMap::new();
+
+    for batch in batches {
+        let (stored, signed_root) = match (&batch.merkle_root, &batch.signed_root) {
+            (Some(stored), Some(signed_root)) => (stored, signed_root),
+            (None, None) => continue,
+            _ => {
+                problems.push(Problem::BadRootSignature { batch: batch.id });
+                roots.insert(batch.id, None);
+                continue;
+            }
+        };
+
+        let root = match gpg_verify(signed_root, keyring) {
+            Ok(statement) => {
+                check_valid_until(batch, &statement, problems);
+                check_root_statement(batch, &statement, problems);
+                root_from_statement(&statement).ok()
+            }
+            Err(_) => None,
+        };
+        match root {
+            Some(root) if root.to_string() == *stored => {
+                roots.insert(batch.id, Some(root));
+            }
+            // The signed root is the one that counts, so still check rows against it
+            Some(root) => {
+                problems.push(Problem::RootMismatch {
+                    batch: batch.id,
+                    expected: stored.clone(),
+                    found: root.to_string(),
+                });
+                roots.insert(batch.id, Some(root));
+            }
+            None => {
+                problems.push(Problem::BadRootSignature { batch: batch.id });
+                roots.insert(batch.id, None);
+            }
+        }
+    }
+
+    roots
+}
+
+/// The signed root has to be for this batch: its network, wallet and indexes.
+/// Otherwise a root could be moved onto a batch it was never signed for.
+fn check_root_statement(batch: &Batch, statement: &str, problems: &mut Vec<Problem>) {
+    let fields = [
+        ("Network", batch.network.clone()),
+        ("Descriptor checksum", batch.descriptor_checksum.clone()),
+        // Wide enough that an edited count can't overflow
+        (
+            "Indexes",
+            format!(
+                "{}..{}",
+                batch.start_index,
+                u64::from(batch.start_index) + u64::from(batch.count)
+            ),
+        ),
+    ];
+    for (field, stored) in fields {
+        let signed = statement_field(statement, field);
+        if signed != Some(stored.as_str()) {
+            problems.push(Problem::RootStatementMismatch {
+                batch: batch.id,
+                field,
+                signed: signed.map(str::to_string),
+                stored,
+            });
+        }
+    }
+}
+
+/// The batch's valid-until date has to be the one that was signed, or an expired
+/// batch could be handed out again just by editing the database
+fn check_valid_until(batch: &Batch, signed_text: &str, problems: &mut Vec<Problem>) {
+    let signed = template::signed_valid_until(signed_text);
+    let matches = match (signed, batch.valid_until.as_deref()) {
+        (Some(signed), stored) => Some(signed) == stored,
+        // The message template can put the date anywhere
+        (None, Some(stored)) => signed_text.contains(stored),
+        (None, None) => true,
+    };
+    if !matches {
+        problems.push(Problem::ValidUntilMismatch {
+            batch: batch.id,
+            signed: signed.map(str::to_string),
+            stored: batch.valid_until.clone(),
+        });
+    }
+}
+
+/// Batches past their validity window, with how many of their addresses were never handed out
+fn expired_batches(batches: &[Batch], entries: &[Entry]) -> Vec<Warning> {
+    batches
+        .iter()
+        .filter(|batch| batch.is_expired())
+        .map(|batch| Warning::Expired {
+            batch: batch.id,
+            valid_until: batch.valid_until.clone().unwrap_or_default(),
+            unused: entries
+                .iter()
+                .filter(|entry| entry.batch_id == batch.id && entry.dispensed_at.is_none())
+                .count(),
+        })
+        .collect()
+}
+
+/// Check a row's Merkle proof if its batch has a root, otherwise its own signature.
+/// Returns the text the row's own signature signs, if it checks out.
+fn check_entry(
+    entry: &Entry,
+    roots: &HashMap<i64, Option<sha256::Hash>>,
+    expected: Option<&str>,
+    keyring: &Keyring,
+    problems: &mut Vec<Problem>,
+) -> Option<String> {
+    match roots.get(&entry.batch_id) {
+        // Already reported against the batch
+        Some(None) => None,
+        Some(Some(root)) => {
+            if entry.statement.is_some() {
+                problems.push(Problem::UnsignedStatement { id: entry.id });
+            }
+            let proven = entry
+                .merkle_proof
+                .as_ref()
+                .and_then(|proof| proof.parse::<Proof>().ok())
+                .map(|proof| proof.verify(entry.derivation_index, &entry.address, root));
+            if proven != Some(true) {
+                problems.push(Problem::BadProof {
+                    id: entry.id,
+                    address: entry.address.clone(),
+                });
+            }
+            None
+        }
+        None => check_signature(entry, expected, keyring, problems),
+    }
+}
+
+/// Check the row's signature and what it signs. Given the expected text the signed
+/// text must be exactly that, without it it only has to include the address.
+fn check_signature(
+    entry: &Entry,
+    expected: Option<&str>,
+    keyring: &Keyring,
+    problems: &mut Vec<Problem>,
+) -> Option<String> {
+    let signed_text = match gpg_verify(&entry.message, keyring) {
+        Ok(signed_text) => signed_text,
+        Err(_) => {
+            problems.push(Problem::BadSignature {
+                id: entry.id,
+                address: entry.address.clone(),
+            });
+            return None;
+        }
+    };
+
+    if let Some(statement) = &entry.statement {
+        if *statement != signed_text {
+            problems.push(Problem::StatementMismatch {
+                id: entry.id,
+                signed: signed_text.clone(),
+                stored: statement.clone(),
+            });
+        }
+    }
+
+    match expected {
+        Some(expected) => {
+            if signed_text != expected {
+                problems.push(Problem::SignedTextMismatch {
+                    id: entry.id,
+                    expected: expected.to_string(),
+                    found: signed_text.clone(),
+                });
+            }
+        }
+        None => {
+            // Addresses are alphanumeric, so split on anything else to find them in
+            // the text, wherever the message template put them
+            let signs_address = signed_text
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .any(|word| word == entry.address);
+            if !signs_address {
+                problems.push(Problem::AddressNotSigned {
+                    id: entry.id,
+                    address: entry.address.clone(),
+                    found: signed_text.clone(),
+                });
+            }
+        }
+    }
+
+    Some(signed_text)
+}
+
+/// Payers see the URI and QR code rather than the signed text, and neither is
+/// signed, so both have to pay the row's address
+fn check_payment_links(db: &Database, entry: &Entry, problems: &mut Vec<Problem>) -> Result<()> {
+    if let Some(uri) = &entry.uri {
+        if uri_address(uri) != Some(entry.address.as_str()) {
+            problems.push(Problem::UriMismatch {
+                id: entry.id,
+                address: entry.address.clone(),
+                uri: uri.clone(),
+            });
+        }
+    }
+
+    for format in [QrFormat::Png, QrFormat::Svg] {
+        if let Some(image) = db.qr_code(entry.id, format)? {
+            // Rendering is deterministic, so the same URI gives the same image
+            let encodes_uri = match &entry.uri {
+                Some(uri) => qr_code(uri, format)? == image,
+                None => false,
+            };
+            if !encodes_uri {
+                problems.push(Problem::QrMismatch {
+                    id: entry.id,
+                    format,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Addresses that appear in more than one row, in row order
+fn find_duplicates(entries: &[Entry]) -> Vec<Problem> {
+    let mut seen: HashMap<&str, Vec<i32>> = HashMap::new();
+    for entry in entries {
+        seen.entry(&entry.address).or_default().push(entry.id);
+    }
+
+    let mut duplicates: Vec<(&str, Vec<i32>)> =
+        seen.into_iter().filter(|(_, ids)| ids.len() > 1).collect();
+    duplicates.sort_by_key(|(_, ids)| ids[0]);
+
+    duplicates
+        .into_iter()
+        .map(|(address, ids)| Problem::Duplicate {
+            address: address.to_string(),
+            ids,
+        })
+        .collect()
+}
+
+/// Look for an address among the indexes in `ranges`. They're only derived the
+/// first time, into `indexes`, since a tampered database may have thousands of
+/// addresses to look up.
+fn find_index(
+    indexes: &mut Option<HashMap<String, u32>>,
+    deriver: &Deriver,
+    address: &str,
+    ranges: &[Range<u32>],
+) -> Result<Option<u32>> {
+    if indexes.is_none() {
+        let mut derived = HashMap::new();
+        for range in ranges {
+            let addresses = deriver.addresses(range.start, range.end - range.start)?;
+            for (index, address) in range.clone().zip(addresses) {
+                derived.insert(address.to_string(), index);
+            }
+        }
+        *indexes = Some(derived);
+    }
+    Ok(indexes
+        .as_ref()
+        .and_then(|indexes| indexes.get(address).copied()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use anyhow::Result;
+    use bdk::bitcoin::Network;
+    use rusqlite::{params, Connection};
+    use tempfile::TempDir;
+
+    use super::{verify_database, verify_signatures, Problem, Report};
+    use crate::{
+        derive::Deriver,
+        gpg::{gpg_clearsign_text, gpg_signing_key, test_gnupg_home},
+        history::BatchRecord,
+        qr::{qr_code, QrFormat},
+        template::{self, Values},
+        Batch, Database, Desc, Entry, Keyring,
+    };
+
+    const MESSAGE: &str = "Donate to Alice";
+
+    fn descriptor() -> Result<Desc> {
+        Ok("wpkh(tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET/0/*)".parse()?)
+    }
+
+    /// Four properly signed addresses, valid until the end of the century
+    fn signed_database() -> Result<(TempDir, PathBuf, Keyring)> {
+        let home = test_gnupg_home();
+        let keyring = Keyring::from_public_key(&home.join("public.asc"))?;
+        let descriptor = descriptor()?;
+        let deriver = Deriver::new(&descriptor, Network::Testnet)?;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ours.db");
+        let db = Database::create(&path)?;
+        let mut batch = Batch::new(&descriptor, Network::Testnet, 0, 4, &gpg_signing_key()?);
+        batch.valid_until = Some("2099-12-31".to_string());
+        let batch_id = db.new_batch(&batch)?;
+
+        for index in 0..4 {
+            let address = deriver.address(index)?.to_string();
+            let values = Values {
+                address: &address,
+                index,
+                date: batch.created_at.get(..10).unwrap(),
+                batch: 1,
+                network: "testnet",
+                uri: None,
+                valid_until: batch.valid_until.as_deref(),
+            };
+            let statement = template::statement(MESSAGE, &values);
+            let mut entry = Entry::new(batch_id, index, &address, &gpg_clearsign_text(&statement)?);
+            entry.statement = Some(statement);
+            db.insert(entry)?;
+        }
+
+        Ok((dir, path, keyring))
+    }
+
+    fn tamper(path: &Path, sql: &str) -> Result<()> {
+        Connection::open(path)?.execute_batch(sql)?;
+        Ok(())
+    }
+
+    /// Check with the xpub, then the way the server does without it
+    fn verify_both(path: &Path, keyring: &Keyring) -> Result<(Report, Report)> {
+        let db = Database::open_read_only(path)?;
+        let full = verify_database(&db, descriptor()?, Network::Testnet, MESSAGE, &[], keyring)?;
+        Ok((full, verify_signatures(&db, keyring)?))
+    }
+
+    #[test]
+    fn passes_untouched_database() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        let (full, server) = verify_both(&path, &keyring)?;
+        assert!(full.is_ok(), "{}", full);
+        assert!(server.is_ok(), "{}", server);
+        assert_eq!((full.checked, server.checked), (4, 4));
+        Ok(())
+    }
+
+    #[test]
+    fn checks_each_batch_against_its_own_message() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        let db = Database::open(&path)?;
+        let history = [BatchRecord {
+            file: path.clone(),
+            start_index: 0,
+            count: 4,
+            created_at: String::new(),
+            message: MESSAGE.to_string(),
+            signing_key_fingerprint: String::new(),
+            sha256: String::new(),
+            valid_until: None,
+        }];
+
+        // The factory's message has been edited since this batch was made
+        let edited = "Donate to Alice and Bob";
+        let report = verify_database(
+            &db,
+            descriptor()?,
+            Network::Testnet,
+            edited,
+            &history,
+            &keyring,
+        )?;
+        assert!(report.is_ok(), "{}", report);
+
+        let report = verify_database(&db, descriptor()?, Network::Testnet, edited, &[], &keyring)?;
+        assert_eq!(report.problems.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn catches_swapped_address() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        let other = Deriver::new(&descriptor()?, Network::Testnet)?.address(9)?;
+        Connection::open(&path)?.execute(
+            "UPDATE entries SET address = ?1 WHERE id = 2",
+            params![other.to_string()],
+        )?;
+
+        let (full, server) = verify_both(&path, &keyring)?;
+        assert!(full.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::AddressMismatch {
+                id: 2,
+                index: 1,
+                derived_at: None,
+                ..
+            }
+        )));
+        assert!(full
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::SignedTextMismatch { id: 2, .. })));
+        assert!(matches!(
+            server.problems[..],
+            [Problem::AddressNotSigned { id: 2, .. }]
+        ));
+
+        // An address moved within the batch is found where it was derived
+        tamper(
+            &path,
+            "UPDATE entries SET address = 'moved' || address WHERE id = 3;
+             UPDATE entries SET address = substr((SELECT address FROM entries WHERE id = 3), 6)
+             WHERE id = 1;",
+        )?;
+        let (full, _) = verify_both(&path, &keyring)?;
+        assert!(full.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::AddressMismatch {
+                id: 1,
+                index: 0,
+                derived_at: Some(2),
+                ..
+            }
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn catches_message_moved_to_another_row() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        tamper(
+            &path,
+            "UPDATE entries SET message = (SELECT message FROM entries WHERE id = 1) WHERE id = 3",
+        )?;
+
+        let (full, server) = verify_both(&path, &keyring)?;
+        assert!(full
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::SignedTextMismatch { id: 3, .. })));
+        assert!(server
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::AddressNotSigned { id: 3, .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn catches_duplicate_row() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        // Our schema won't allow one, so rebuild the table without its constraints
+        tamper(
+            &path,
+            "PRAGMA foreign_keys = OFF;
+             CREATE TABLE copied AS SELECT * FROM entries;
+             DROP TABLE entries;
+             ALTER TABLE copied RENAME TO entries;
+             INSERT INTO entries SELECT * FROM entries WHERE id = 1;
+             UPDATE entries SET id = 5 WHERE rowid = (SELECT max(rowid) FROM entries);",
+        )?;
+
+        let (full, server) = verify_both(&path, &keyring)?;
+        for report in &[full, server] {
+            assert!(report.problems.iter().any(|problem| matches!(
+                problem,
+                Problem::Duplicate { ids, .. } if ids[..] == [1, 5]
+            )));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn catches_missing_index() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        tamper(&path, "DELETE FROM entries WHERE derivation_index = 2")?;
+
+        let (full, _) = verify_both(&path, &keyring)?;
+        assert!(matches!(
+            full.problems[..],
+            [Problem::MissingIndex { index: 2, .. }]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn catches_edited_count() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        tamper(&path, "UPDATE batches SET count = 2000000000")?;
+
+        // Lists a few of the missing indexes rather than all of them
+        let (full, _) = verify_both(&path, &keyring)?;
+        let listed = full
+            .problems
+            .iter()
+            .filter(|problem| matches!(problem, Problem::MissingIndex { .. }))
+            .count();
+        assert_eq!(listed, 100);
+        assert!(full.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::ManyMissing {
+                more: 1_999_999_896,
+                ..
+            }
+        )));
+
+        tamper(&path, "UPDATE batches SET count = 4294967295")?;
+        let (full, _) = verify_both(&path, &keyring)?;
+        assert!(full
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::InvalidRange { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn catches_bad_signature() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        tamper(
+            &path,
+            "UPDATE entries SET message = replace(message, 'Alice', 'Mallory') WHERE id = 4",
+        )?;
+
+        let (full, server) = verify_both(&path, &keyring)?;
+        for report in &[full, server] {
+            assert!(matches!(
+                report.problems[..],
+                [Problem::BadSignature { id: 4, .. }]
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn catches_tampered_payment_links() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        {
+            let db = Database::open(&path)?;
+            for entry in db.entries()? {
+                let uri = format!("bitcoin:{}?label=Alice", entry.address);
+                tamper(
+                    &path,
+                    &format!("UPDATE entries SET uri = '{}' WHERE id = {}", uri, entry.id),
+                )?;
+                db.insert_qr_code(entry.id, QrFormat::Svg, &qr_code(&uri, QrFormat::Svg)?)?;
+            }
+        }
+        let (full, server) = verify_both(&path, &keyring)?;
+        assert!(full.is_ok(), "{}", full);
+        assert!(server.is_ok(), "{}", server);
+
+        // Send the payer somewhere else without touching the signed text
+        let attacker = Deriver::new(&descriptor()?, Network::Testnet)?.address(9)?;
+        let uri = format!("bitcoin:{}?label=Alice", attacker);
+        tamper(
+            &path,
+            &format!("UPDATE entries SET uri = '{}' WHERE id = 2", uri),
+        )?;
+        let image = qr_code(&uri, QrFormat::Svg)?;
+        Connection::open(&path)?.execute(
+            "UPDATE qr_codes SET image = ?1 WHERE entry_id = 3",
+            params![image],
+        )?;
+
+        let (full, server) = verify_both(&path, &keyring)?;
+        for report in &[full, server] {
+            assert!(
+                matches!(
+                    report.problems[..],
+                    [
+                        Problem::UriMismatch { id: 2, .. },
+                        Problem::QrMismatch { id: 2, .. },
+                        Problem::QrMismatch { id: 3, .. },
+                    ]
+                ),
+                "{}",
+                report
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn catches_edited_valid_until() -> Result<()> {
+        let (_dir, path, keyring) = signed_database()?;
+        tamper(&path, "UPDATE batches SET valid_until = '2199-12-31'")?;
+
+        let (full, server) = verify_both(&path, &keyring)?;
+        assert!(full
+            .problems
+            .iter()
+            .all(|problem| matches!(problem, Problem::SignedTextMismatch { .. })));
+        assert_eq!(full.problems.len(), 4);
+        assert!(matches!(
+            server.problems[..],
+            [Problem::ValidUntilMismatch { batch: 1, .. }]
+        ));
+
+        // Backdating it is caught too, and warned about
+        tamper(&path, "UPDATE batches SET valid_until = '2021-03-20'")?;
+        let (full, server) = verify_both(&path, &keyring)?;
+        assert!(!server.is_ok());
+        assert_eq!(full.warnings.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+// Donations can land on any address in a batch, in any order, so the wallet
+// watching for them has to look past every address generated so far. Most
+// wallets only look 20 ahead out of the box.
+
+use bdk::bitcoin::Network;
+
+use crate::Desc;
+
+/// How far ahead wallets look out of the box
+const DEFAULT_GAP_LIMITS: &[(&str, u32)] = &[
+    ("Electrum", 20),
+    ("Sparrow", 20),
+    ("Bitcoin Core (keypool)", 1000),
+];
+
+/// The gap limit a wallet needs to see a payment to any address up to the end
+/// of a batch of `count` starting at `start_index`. Wallets count the gap from
+/// the last address they've seen paid, and in a donation pool that can be none
+/// at all, since addresses go out in any order and plenty are never paid. So
+/// the gap has to reach from index 0 to the end of the batch.
+pub fn required_gap_limit(start_index: u32, count: u32) -> u32 {
+    start_index + count
+}
+
+/// One line per wallet whose default gap limit is too small for the batch
+pub fn gap_limit_warnings(start_index: u32, count: u32) -> Vec<String> {
+    let needed = required_gap_limit(start_index, count);
+    DEFAULT_GAP_LIMITS
+        .iter()
+        .filter(|(_, default)| *default < needed)
+        .map(|(wallet, default)| {
+            format!(
+                "{} only looks {} addresses ahead by default, this batch needs a gap limit of {}",
+                wallet, default, needed
+            )
+        })
+        .collect()
+}
+
+/// How to get each wallet to watch every address up to the end of the batch
+pub fn import_hints(descriptor: &Desc, network: Network, start_index: u32, count: u32) -> String {
+    let needed = required_gap_limit(start_index, count);
+    let last_index = (start_index + count).saturating_sub(1);
+    let cli = match network {
+        Network::Bitcoin => "bitcoin-cli",
+        Network::Testnet => "bitcoin-cli -testnet",
+        Network::Regtest => "bitcoin-cli -regtest",
+        Network::Signet => "bitcoin-cli -signet",
+    };
+
+    format!(
+        "To watch these addresses for payments:
+
+Bitcoin Core, in a watch-only descriptor wallet:
+  {cli} createwallet donations true true \"\" false true
+  {cli} -rpcwallet=donations importdescriptors '[{{\"desc\": \"{descriptor}\", \"timestamp\": \"now\", \"range\": [0, {last_index}], \"watchonly\": true}}]'
+  (use an older timestamp if addresses from earlier batches may already have been paid)
+
+Sparrow: Settings > Advanced > Gap limit, set it to at least {needed}
+
+Electrum: in the Console tab run wallet.change_gap_limit({needed}) and restart",
+        cli = cli,
+        descriptor = descriptor,
+        last_index = last_index,
+        needed = needed,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bdk::{bitcoin::Network, descriptor::ExtendedDescriptor};
+
+    use super::{gap_limit_warnings, import_hints};
+
+    #[test]
+    fn warns_about_small_gap_limits() -> Result<()> {
+        assert!(gap_limit_warnings(0, 20).is_empty());
+        assert_eq!(gap_limit_warnings(0, 100).len(), 2);
+        assert_eq!(gap_limit_warnings(0, 5000).len(), 3);
+        // A small batch after a big one still needs the wallet to look past both
+        assert_eq!(gap_limit_warnings(990, 10).len(), 2);
+
+        let descriptor: ExtendedDescriptor = "wpkh(tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET/0/*)".parse()?;
+        let hints = import_hints(&descriptor, Network::Testnet, 1000, 500);
+        assert!(hints.contains("bitcoin-cli -testnet -rpcwallet=donations importdescriptors"));
+        assert!(hints.contains("\"range\": [0, 1499]"));
+        assert!(hints.contains("#pjd3nkdg"));
+        assert!(hints.contains("wallet.change_gap_limit(1500)"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,520 @@
+// Check the chain for payments to the addresses in a database, through an
+// Electrum server or an Esplora HTTP API, and record what's been paid.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+use bdk::{
+    bitcoin::{Address, Amount},
+    electrum_client::{self, ElectrumApi},
+};
+use serde::Deserialize;
+
+use crate::{
+    bip21::uri_amount,
+    webhook::{payload, Webhook},
+    Database, Payment,
+};
+
+/// Somewhere to look up an address's transactions
+pub trait Backend {
+    /// Every transaction paying `address`, with how much it paid to it
+    fn history(&self, address: &Address) -> Result<Vec<Payment>>;
+
+    /// Height of the best block
+    fn tip_height(&self) -> Result<u32>;
+}
+
+/// `tcp://` and `ssl://` URLs are Electrum servers, `http://` and `https://` are Esplora
+pub fn connect(url: &str) -> Result<Box<dyn Backend>> {
+    if url.starts_with("tcp://") || url.starts_with("ssl://") {
+        Ok(Box::new(Electrum::new(url)?))
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Box::new(Esplora::new(url)))
+    } else {
+        bail!(
+            "Don't know how to watch through {}, expected an Electrum tcp:// or ssl:// URL or an Esplora http(s):// one",
+            url
+        )
+    }
+}
+
+pub struct Electrum {
+    client: electrum_client::Client,
+}
+
+impl Electrum {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            client: electrum_client::Client::new(url)?,
+        })
+    }
+}
+
+impl Backend for Electrum {
+    fn history(&self, address: &Address) -> Result<Vec<Payment>> {
+        let script = address.script_pubkey();
+        let history = self.client.script_get_history(&script)?;
+        let txids: Vec<_> = history.iter().map(|item| item.tx_hash).collect();
+        let transactions = self.client.batch_transaction_get(&txids)?;
+
+        Ok(history
+            .iter()
+            .zip(transactions)
+            .map(|(item, tx)| Payment {
+                txid: item.tx_hash.to_string(),
+                amount: tx
+                    .output
+                    .iter()
+                    .filter(|output| output.script_pubkey == script)
+                    .map(|output| output.value)
+                    .sum(),
+                // 0 and -1 both mean it's still in the mempool
+                height: if item.height > 0 {
+                    Some(item.height as u32)
+                } else {
+                    None
+                },
+            })
+            // Transactions spending from the address show up in its history too
+            .filter(|payment| payment.amount > 0)
+            .collect())
+    }
+
+    fn tip_height(&self) -> Result<u32> {
+        Ok(self.client.block_headers_subscribe()?.height as u32)
+    }
+}
+
+/// Esplora only returns this many confirmed transactions per page
+const ESPLORA_PAGE: usize = 25;
+
+pub struct Esplora {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct EsploraTx {
+    txid: String,
+    vout: Vec<EsploraOutput>,
+    status: EsploraStatus,
+}
+
+#[derive(Deserialize)]
+struct EsploraOutput {
+    scriptpubkey_address: Option<String>,
+    value: u64,
+}
+
+#[derive(Deserialize)]
+struct EsploraStatus {
+    confirmed: bool,
+    block_height: Option<u32>,
+}
+
+impl Esplora {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<ureq::Response> {
+        let url = format!("{}{}", self.url, path);
+        let response = ureq::get(&url).timeout_connect(10_000).call();
+        if let Some(error) = response.synthetic_error() {
+            bail!("Couldn't reach {}: {}", url, error)
+        }
+        if !response.ok() {
+            bail!("{} answered {}", url, response.status_line())
+        }
+
+        Ok(response)
+    }
+
+    fn txs(&self, path: &str) -> Result<Vec<EsploraTx>> {
+        self.get(path)?
+            .into_json_deserialize()
+            .map_err(|error| anyhow!("Unexpected response from {}: {}", self.url, error))
+    }
+}
+
+impl Backend for Esplora {
+    fn history(&self, address: &Address) -> Result<Vec<Payment>> {
+        let address = address.to_string();
+        // The first page has the mempool and the newest confirmed transactions,
+        // older ones are paged through after the last confirmed txid seen
+        let mut txs = self.txs(&format!("/address/{}/txs", address))?;
+        let mut confirmed = txs.iter().filter(|tx| tx.status.confirmed).count();
+        while confirmed == ESPLORA_PAGE {
+            let last = &txs[txs.len() - 1].txid;
+            let page = self.txs(&format!("/address/{}/txs/chain/{}", address, last))?;
+            confirmed = page.len();
+            txs.extend(page);
+        }
+
+        Ok(txs
+            .iter()
+            .map(|tx| Payment {
+                txid: tx.txid.clone(),
+                amount: tx
+                    .vout
+                    .iter()
+                    .filter(|output| output.scriptpubkey_address.as_deref() == Some(&address))
+                    .map(|output| output.value)
+                    .sum(),
+                height: if tx.status.confirmed {
+                    tx.status.block_height
+                } else {
+                    None
+                },
+            })
+            .filter(|payment| payment.amount > 0)
+            .collect())
+    }
+
+    fn tip_height(&self) -> Result<u32> {
+        let height = self.get("/blocks/tip/height")?.into_string()?;
+        Ok(height.trim().parse()?)
+    }
+}
+
+/// Something about an address's payments worth a closer look
+#[derive(Debug, PartialEq)]
+pub enum Flag {
+    /// Paid by more than one transaction, so payments can be linked
+    Reused { address: String, txids: Vec<String> },
+    /// Received more than its payment URI asked for
+    Overpaid {
+        address: String,
+        expected: Amount,
+        received: Amount,
+    },
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Flag::Reused { address, txids } => write!(
+                f,
+                "{} was paid {} times: {}",
+                address,
+                txids.len(),
+                txids.join(", ")
+            ),
+            Flag::Overpaid {
+                address,
+                expected,
+                received,
+            } => write!(
+                f,
+                "{} asked for {} but received {}",
+                address, expected, received
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WatchOptions {
+    /// Only check addresses that have been handed out
+    pub dispensed_only: bool,
+    /// Where to send payments to dispensed addresses
+    pub webhook: Option<Webhook>,
+}
+
+/// Outcome of one pass over a database
+#[derive(Debug, Default)]
+pub struct WatchReport {
+    pub checked: usize,
+    /// Addresses with at least one payment
+    pub paid: usize,
+    pub confirmed: Amount,
+    pub unconfirmed: Amount,
+    /// Payments seen for the first time on this pass
+    pub new_payments: Vec<(String, Payment)>,
+    pub flags: Vec<Flag>,
+    /// Webhook events delivered on this pass
+    pub notified: usize,
+    /// Why the webhook couldn't be reached. Undelivered events are tried again next pass.
+    pub webhook_error: Option<String>,
+}
+
+impl fmt::Display for WatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (address, payment) in &self.new_payments {
+            write!(
+                f,
+                "New payment of {} to {} in {}",
+                Amount::from_sat(payment.amount),
+                address,
+                payment.txid
+            )?;
+            match payment.height {
+                Some(height) => writeln!(f, " (confirmed at height {})", height)?,
+                None => writeln!(f, " (unconfirmed)")?,
+            }
+        }
+        for flag in &self.flags {
+            writeln!(f, "{}", flag)?;
+        }
+        if self.notified > 0 {
+            writeln!(f, "Sent {} webhook events", self.notified)?;
+        }
+        if let Some(error) = &self.webhook_error {
+            writeln!(f, "Webhook failed, will try again next time: {}", error)?;
+        }
+        write!(
+            f,
+            "Checked {} addresses, {} paid: {} confirmed, {} unconfirmed",
+            self.checked, self.paid, self.confirmed, self.unconfirmed
+        )
+    }
+}
+
+/// Look up every address in the database and record its payments
+pub fn watch(
+    db: &mut Database,
+    backend: &dyn Backend,
+    options: &WatchOptions,
+) -> Result<WatchReport> {
+    let mut report = WatchReport::default();
+
+    for entry in db.entries()? {
+        if options.dispensed_only && entry.dispensed_at.is_none() {
+            continue;
+        }
+
+        let address = Address::from_str(&entry.address)?;
+        let payments = backend.history(&address)?;
+        report.checked += 1;
+
+        for payment in db.sync_payments(entry.id, &payments)? {
+            report.new_payments.push((entry.address.clone(), payment));
+        }
+        if payments.is_empty() {
+            continue;
+        }
+
+        report.paid += 1;
+        let mut received = Amount::ZERO;
+        for payment in &payments {
+            let amount = Amount::from_sat(payment.amount);
+            received += amount;
+            match payment.height {
+                Some(_) => report.confirmed += amount,
+                None => report.unconfirmed += amount,
+            }
+        }
+
+        if payments.len() > 1 {
+            report.flags.push(Flag::Reused {
+                address: entry.address.clone(),
+                txids: payments
+                    .iter()
+                    .map(|payment| payment.txid.clone())
+                    .collect(),
+            });
+        }
+        if let Some(expected) = entry.uri.as_deref().and_then(uri_amount) {
+            if received > expected {
+                report.flags.push(Flag::Overpaid {
+                    address: entry.address.clone(),
+                    expected,
+                    received,
+                });
+            }
+        }
+    }
+
+    if let Some(webhook) = &options.webhook {
+        notify(db, backend, webhook, &mut report)?;
+    }
+
+    Ok(report)
+}
+
+/// Send every event the webhook hasn't accepted yet, oldest first. Stops at
+/// the first one that fails so a receiver that's down doesn't hold up the pass.
+fn notify(
+    db: &Database,
+    backend: &dyn Backend,
+    webhook: &Webhook,
+    report: &mut WatchReport,
+) -> Result<()> {
+    let tip = backend.tip_height()?;
+
+    for (event, entry, payment) in db.pending_notifications(webhook.confirmations, tip)? {
+        let confirmations = payment
+            .height
+            .map_or(0, |height| (tip + 1).saturating_sub(height));
+        match webhook.send(&payload(event, &entry, &payment, confirmations)) {
+            Ok(()) => {
+                db.mark_notified(entry.id, &payment.txid, event)?;
+                report.notified += 1;
+            }
+            Err(error) => {
+                report.webhook_error = Some(error.to_string());
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use anyhow::Result;
+    use bdk::{
+        bitcoin::{Amount, Network},
+        descriptor::ExtendedDescriptor,
+    };
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+    use tiny_http::{Response, Server};
+
+    use super::{watch, Esplora, Flag, WatchOptions};
+    use crate::{
+        database::test_batch,
+        derive::Deriver,
+        webhook::{mock_receiver, Webhook},
+        Database, Entry,
+    };
+
+    /// Two addresses, the first asking for 0.0005 BTC, and a stand-in Esplora
+    /// that knows two payments to it with the tip at 1,900,001
+    fn paid_database() -> Result<(Database, Esplora, TempDir, Vec<String>)> {
+        let descriptor: ExtendedDescriptor = "wpkh(tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET/0/*)".parse()?;
+        let addresses: Vec<String> = Deriver::new(&descriptor, Network::Testnet)?
+            .addresses(0, 2)?
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        let dir = tempfile::tempdir()?;
+        let db = Database::create(&dir.path().join("ours.db"))?;
+        let batch_id = db.new_batch(&test_batch())?;
+        let mut first = Entry::new(batch_id, 0, &addresses[0], "signed");
+        first.uri = Some(format!("bitcoin:{}?amount=0.0005", addresses[0]));
+        db.insert(first)?;
+        db.insert(Entry::new(batch_id, 1, &addresses[1], "signed"))?;
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr());
+        let paid = format!("/address/{}/txs", addresses[0]);
+        let history = json!([
+            {
+                "txid": "bb",
+                "vout": [{"scriptpubkey_address": addresses[0], "value": 10_000}],
+                "status": {"confirmed": false}
+            },
+            {
+                "txid": "aa",
+                "vout": [
+                    {"scriptpubkey_address": addresses[0], "value": 50_000},
+                    {"scriptpubkey_address": "tb1qsomeoneelse", "value": 7_000}
+                ],
+                "status": {"confirmed": true, "block_height": 1_900_000}
+            }
+        ]);
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let body = if request.url() == paid {
+                    history.to_string()
+                } else if request.url() == "/blocks/tip/height" {
+                    "1900001".to_string()
+                } else {
+                    "[]".to_string()
+                };
+                request.respond(Response::from_string(body)).unwrap();
+            }
+        });
+
+        Ok((db, Esplora::new(&url), dir, addresses))
+    }
+
+    #[test]
+    fn records_payments_from_esplora() -> Result<()> {
+        let (mut db, backend, _dir, addresses) = paid_database()?;
+        let report = watch(&mut db, &backend, &WatchOptions::default())?;
+        assert_eq!((report.checked, report.paid), (2, 1));
+        assert_eq!(report.confirmed, Amount::from_sat(50_000));
+        assert_eq!(report.unconfirmed, Amount::from_sat(10_000));
+        assert_eq!(report.new_payments.len(), 2);
+        assert_eq!(
+            report.flags,
+            vec![
+                Flag::Reused {
+                    address: addresses[0].clone(),
+                    txids: vec!["bb".to_string(), "aa".to_string()],
+                },
+                Flag::Overpaid {
+                    address: addresses[0].clone(),
+                    expected: Amount::from_sat(50_000),
+                    received: Amount::from_sat(60_000),
+                },
+            ]
+        );
+
+        let entry = db.entry(1)?.unwrap();
+        assert!(entry.paid_at.is_some());
+        assert_eq!(db.payments(1)?.len(), 2);
+
+        // Nothing new the second time round
+        let report = watch(&mut db, &backend, &WatchOptions::default())?;
+        assert!(report.new_payments.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn notifies_webhook_of_dispensed_payments() -> Result<()> {
+        let (url, received) = mock_receiver(1);
+        let mut webhook = Webhook::new(&url);
+        webhook.confirmations = 3;
+        webhook.backoff = Duration::from_millis(1);
+        let mut options = WatchOptions {
+            dispensed_only: false,
+            webhook: Some(webhook),
+        };
+
+        // Nothing's been handed out yet, and the paid address never will be
+        {
+            let (mut db, backend, _dir, addresses) = paid_database()?;
+            assert_eq!(watch(&mut db, &backend, &options)?.notified, 0);
+            assert_eq!(db.next_unused(None)?.unwrap().address, addresses[1]);
+        }
+
+        let (mut db, backend, _dir, _) = paid_database()?;
+        db.next_unused(Some("order 42"))?;
+        let report = watch(&mut db, &backend, &options)?;
+        assert_eq!(report.notified, 2);
+        {
+            let received = received.lock().unwrap();
+            let events: Vec<Value> = received
+                .iter()
+                .map(|(_, body)| serde_json::from_str(body))
+                .collect::<Result<_, _>>()?;
+            assert_eq!(events[0]["event"], "payment.seen");
+            assert_eq!(events[0]["txid"], "bb");
+            assert_eq!(events[0]["confirmations"], 0);
+            assert_eq!(events[0]["label"], "order 42");
+            assert_eq!(events[0]["derivation_index"], 0);
+            assert_eq!(events[1]["txid"], "aa");
+            assert_eq!(events[1]["amount"], 50_000);
+            assert_eq!(events[1]["confirmations"], 2);
+        }
+
+        // "aa" is only 2 deep, so lowering the bar sends its confirmation once
+        options.webhook.as_mut().unwrap().confirmations = 2;
+        assert_eq!(watch(&mut db, &backend, &options)?.notified, 1);
+        assert_eq!(watch(&mut db, &backend, &options)?.notified, 0);
+        let received = received.lock().unwrap();
+        assert!(received[2].1.contains("payment.confirmed"));
+
+        Ok(())
+    }
+}
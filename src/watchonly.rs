@@ -0,0 +1,277 @@
+// Set up a watch-only wallet that tracks exactly the addresses the factory
+// has generated so far, for Bitcoin Core, Electrum/Sparrow and BDK.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use bdk::{
+    bitcoin::{
+        util::{
+            base58,
+            bip32::{ChildNumber, ExtendedPubKey},
+        },
+        Network,
+    },
+    miniscript::{
+        descriptor::{DescriptorType, DescriptorXKey, Wildcard},
+        DescriptorPublicKey, ForEach, ForEachKey, TranslatePk2,
+    },
+};
+use serde_json::{json, Value};
+
+use crate::{wallet_hints::required_gap_limit, Desc, Factory};
+
+pub struct WatchOnly {
+    pub receive: Desc,
+    /// The same keys on the change branch, when the descriptor is on `/0/*`
+    pub change: Option<Desc>,
+    pub network: Network,
+    /// Highest index generated so far
+    pub last_index: u32,
+}
+
+impl WatchOnly {
+    pub fn new(factory: &Factory) -> Result<Self> {
+        if factory.next_index == 0 {
+            bail!("No addresses have been generated yet")
+        }
+
+        Ok(Self {
+            receive: factory.descriptor.clone(),
+            change: change_descriptor(&factory.descriptor),
+            network: factory.network,
+            last_index: factory.next_index - 1,
+        })
+    }
+
+    /// A wallet has to look past every generated address to be sure it sees them all
+    fn gap_limit(&self) -> u32 {
+        required_gap_limit(0, self.last_index + 1).max(20)
+    }
+
+    /// Request body for Bitcoin Core's `importdescriptors`. `timestamp` is
+    /// "now" or the UNIX time to rescan from.
+    pub fn core_import(&self, timestamp: &Value) -> Value {
+        let mut requests = vec![json!({
+            "desc": self.receive.to_string(),
+            "timestamp": timestamp,
+            "range": [0, self.last_index],
+            "active": true,
+            "internal": false,
+            "watchonly": true,
+        })];
+        if let Some(change) = &self.change {
+            requests.push(json!({
+                "desc": change.to_string(),
+                "timestamp": timestamp,
+                "range": [0, self.last_index],
+                "active": true,
+                "internal": true,
+                "watchonly": true,
+            }));
+        }
+
+        json!(requests)
+    }
+
+    /// An Electrum wallet file, which Sparrow can import too. Electrum only
+    /// knows single-key wallets on the usual `/0/*` and `/1/*` branches.
+    pub fn electrum_wallet(&self) -> Result<Value> {
+        let xkey = match single_xkey(&self.receive) {
+            Some(xkey) => xkey,
+            None => bail!("Electrum and Sparrow files only work for single-key descriptors"),
+        };
+        if xkey.derivation_path.as_ref() != [ChildNumber::Normal { index: 0 }]
+            || xkey.wildcard != Wildcard::Unhardened
+        {
+            bail!("Electrum and Sparrow files need the descriptor to end in /0/*")
+        }
+
+        let xpub = slip132_xpub(&xkey, self.receive.desc_type())?;
+        let (root_fingerprint, derivation) = match &xkey.origin {
+            Some((fingerprint, path)) => (json!(fingerprint.to_string()), json!(path.to_string())),
+            None => (Value::Null, Value::Null),
+        };
+
+        Ok(json!({
+            "keystore": {
+                "type": "bip32",
+                "xpub": xpub,
+                "xprv": null,
+                "root_fingerprint": root_fingerprint,
+                "derivation": derivation,
+                "label": "Address Factory",
+                "pw_hash_version": 1,
+            },
+            "wallet_type": "standard",
+            "use_encryption": false,
+            "seed_version": 18,
+            "gap_limit": self.gap_limit(),
+        }))
+    }
+
+    /// External and internal descriptors, the way `bdk::Wallet::new` takes them
+    pub fn bdk_descriptors(&self) -> Value {
+        json!({
+            "network": self.network.to_string(),
+            "external": self.receive.to_string(),
+            "internal": self.change.as_ref().map(ToString::to_string),
+            "last_index": self.last_index,
+        })
+    }
+}
+
+/// Swap the `/0/*` at the end of every key for `/1/*`
+pub fn change_descriptor(descriptor: &Desc) -> Option<Desc> {
+    let mut changed = true;
+    descriptor.for_each_key(|key| {
+        if let ForEach::Key(DescriptorPublicKey::XPub(xkey)) = key {
+            let path: &[ChildNumber] = xkey.derivation_path.as_ref();
+            changed &= path.last() == Some(&ChildNumber::Normal { index: 0 });
+        } else {
+            changed = false;
+        }
+        true
+    });
+    if !changed {
+        return None;
+    }
+
+    Some(descriptor.translate_pk2_infallible(|key| match key {
+        DescriptorPublicKey::XPub(xkey) => {
+            let mut xkey = xkey.clone();
+            let mut path: Vec<ChildNumber> = xkey.derivation_path.into();
+            path.pop();
+            path.push(ChildNumber::Normal { index: 1 });
+            xkey.derivation_path = path.into();
+            DescriptorPublicKey::XPub(xkey)
+        }
+        single => single.clone(),
+    }))
+}
+
+fn single_xkey(descriptor: &Desc) -> Option<DescriptorXKey<ExtendedPubKey>> {
+    let mut keys = vec![];
+    descriptor.for_each_key(|key| {
+        keys.push(key.as_key().clone());
+        true
+    });
+
+    match keys.as_slice() {
+        [DescriptorPublicKey::XPub(xkey)] => Some(xkey.clone()),
+        _ => None,
+    }
+}
+
+/// Electrum works out the script type from the key's SLIP-132 prefix
+fn slip132_xpub(
+    xkey: &DescriptorXKey<ExtendedPubKey>,
+    desc_type: DescriptorType,
+) -> Result<String> {
+    let mainnet = xkey.xkey.network == Network::Bitcoin;
+    let version = match (desc_type, mainnet) {
+        (DescriptorType::Pkh, true) => slip132::VERSION_MAGIC_XPUB,
+        (DescriptorType::Pkh, false) => slip132::VERSION_MAGIC_TPUB,
+        (DescriptorType::ShWpkh, true) => slip132::VERSION_MAGIC_YPUB,
+        (DescriptorType::ShWpkh, false) => slip132::VERSION_MAGIC_UPUB,
+        (DescriptorType::Wpkh, true) => slip132::VERSION_MAGIC_ZPUB,
+        (DescriptorType::Wpkh, false) => slip132::VERSION_MAGIC_VPUB,
+        _ => bail!("Electrum and Sparrow files only work for pkh, wpkh and sh(wpkh)"),
+    };
+
+    let mut data = xkey.xkey.encode();
+    data[..4].copy_from_slice(&version);
+    Ok(base58::check_encode_slice(&data))
+}
+
+/// Write every format that fits the factory's descriptor into `out`
+pub fn export_watchonly(factory: &Factory, out: &Path, timestamp: &Value) -> Result<Vec<PathBuf>> {
+    let watch_only = WatchOnly::new(factory)?;
+    fs::create_dir_all(out)?;
+    let mut written = vec![];
+
+    let core = out.join("bitcoin-core-importdescriptors.json");
+    fs::write(
+        &core,
+        serde_json::to_string_pretty(&watch_only.core_import(timestamp))?,
+    )?;
+    written.push(core);
+
+    match watch_only.electrum_wallet() {
+        Ok(wallet) => {
+            let electrum = out.join("electrum-watchonly.json");
+            fs::write(&electrum, serde_json::to_string_pretty(&wallet)?)?;
+            written.push(electrum);
+        }
+        Err(error) => println!("Skipping the Electrum/Sparrow wallet: {}", error),
+    }
+
+    let bdk = out.join("bdk-descriptors.json");
+    fs::write(
+        &bdk,
+        serde_json::to_string_pretty(&watch_only.bdk_descriptors())?,
+    )?;
+    written.push(bdk);
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use anyhow::Result;
+    use bdk::{
+        bitcoin::{util::bip32::ExtendedPubKey, Network},
+        descriptor::ExtendedDescriptor,
+    };
+    use serde_json::json;
+    use slip132::FromSlip132;
+
+    use super::{change_descriptor, WatchOnly};
+
+    #[test]
+    fn exports_watch_only_wallets() -> Result<()> {
+        let receive: ExtendedDescriptor = "wpkh([78cf94e5/84'/1'/123']tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET/0/*)".parse()?;
+        let watch_only = WatchOnly {
+            change: change_descriptor(&receive),
+            receive,
+            network: Network::Testnet,
+            last_index: 99,
+        };
+
+        let change = watch_only.change.as_ref().unwrap().to_string();
+        assert!(change.contains("/1/*)"));
+
+        let core = watch_only.core_import(&json!("now"));
+        assert_eq!(core[0]["range"], json!([0, 99]));
+        assert_eq!(core[1]["internal"], true);
+        assert_eq!(core[1]["desc"], change);
+
+        let electrum = watch_only.electrum_wallet()?;
+        let xpub = electrum["keystore"]["xpub"].as_str().unwrap();
+        // Coldcard's vpub for the same account
+        assert_eq!(xpub, "vpub5Y5a91QvDT45EnXQaKeuvJupVvX8f9BiywDcadSTtaeJ1VgJPPXMitnYsqd9k7GnEqh44FKJ5McJfu6KrihFXhAmvSWgm7BAVVK8Gupu4fL");
+        assert_eq!(
+            ExtendedPubKey::from_slip132_str(xpub)?,
+            ExtendedPubKey::from_str("tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET")?
+        );
+        assert_eq!(electrum["keystore"]["derivation"], "m/84'/1'/123'");
+        assert_eq!(electrum["gap_limit"], 100);
+
+        let multisig: ExtendedDescriptor = "wsh(multi(1,tpubDC7jGaaSE66VDB6VhEDFYQSCAyugXmfnMnrMVyHNzW9wryyTxvha7TmfAHd7GRXrr2TaAn2HXn9T8ep4gyNX1bzGiieqcTUNcu2poyntrET/0/*,tpubDCiHGUNYdRRGoSH22j8YnruUKgguCK1CC2NFQUf9PApeZh8ewAJJWGMUrhggDNK73iCTanWXv1RN5FYemUH8UrVUBjqDb8WF2VoKmDh9UTo/0/*))".parse()?;
+        let watch_only = WatchOnly {
+            change: change_descriptor(&multisig),
+            receive: multisig,
+            network: Network::Testnet,
+            last_index: 99,
+        };
+        assert!(watch_only.change.is_some());
+        assert!(watch_only.electrum_wallet().is_err());
+
+        Ok(())
+    }
+}
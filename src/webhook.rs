@@ -0,0 +1,190 @@
+// Tell a shop backend about payments to the addresses it handed out. Each
+// request is a JSON body signed with HMAC-SHA256 so the receiver can check
+// it came from us.
+
+use std::{fmt, thread, time::Duration};
+
+use anyhow::{bail, Result};
+use bdk::bitcoin::hashes::{
+    hmac::{Hmac, HmacEngine},
+    sha256, Hash, HashEngine,
+};
+use serde_json::{json, Value};
+
+use crate::{Entry, Payment};
+
+/// Header carrying `sha256=<hex HMAC of the body>`
+pub const SIGNATURE_HEADER: &str = "X-Address-Factory-Signature";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaymentEvent {
+    /// A transaction paying the address showed up, usually in the mempool
+    Seen,
+    /// The transaction has the confirmations the webhook asks for
+    Confirmed,
+}
+
+impl fmt::Display for PaymentEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentEvent::Seen => write!(f, "payment.seen"),
+            PaymentEvent::Confirmed => write!(f, "payment.confirmed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    /// Sign each body with this, if set
+    pub secret: Option<String>,
+    /// Confirmations before sending `payment.confirmed`
+    pub confirmations: u32,
+    /// Tries after the first one fails, waiting twice as long each time
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            secret: None,
+            confirmations: 1,
+            retries: 5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// POST the event, retrying with exponential backoff until the receiver answers 2xx
+    pub fn send(&self, payload: &Value) -> Result<()> {
+        let body = payload.to_string();
+        let mut wait = self.backoff;
+        let mut attempt = 0;
+
+        loop {
+            let mut request = ureq::post(&self.url);
+            request
+                .timeout_connect(10_000)
+                .set("Content-Type", "application/json");
+            if let Some(event) = payload["event"].as_str() {
+                request.set("X-Address-Factory-Event", event);
+            }
+            if let Some(secret) = &self.secret {
+                request.set(SIGNATURE_HEADER, &format!("sha256={}", sign(secret, &body)));
+            }
+
+            let response = request.send_string(&body);
+            if response.ok() {
+                return Ok(());
+            }
+            if attempt == self.retries {
+                match response.synthetic_error() {
+                    Some(error) => bail!("Couldn't reach {}: {}", self.url, error),
+                    None => bail!("{} answered {}", self.url, response.status_line()),
+                }
+            }
+
+            thread::sleep(wait);
+            wait *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `body`, the way receivers should recompute it
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+pub fn payload(event: PaymentEvent, entry: &Entry, payment: &Payment, confirmations: u32) -> Value {
+    json!({
+        "event": event.to_string(),
+        "id": entry.id,
+        "address": entry.address,
+        "derivation_index": entry.derivation_index,
+        "label": entry.label,
+        "txid": payment.txid,
+        "amount": payment.amount,
+        "confirmations": confirmations,
+    })
+}
+
+/// Signature header and body of each request a mock receiver accepted
+#[cfg(test)]
+pub(crate) type Received = std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>;
+
+/// A receiver that fails the first `failures` requests, then records the rest
+#[cfg(test)]
+pub(crate) fn mock_receiver(failures: usize) -> (String, Received) {
+    use std::sync::{Arc, Mutex};
+    use tiny_http::{Response, Server};
+
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", server.server_addr());
+    let received = Arc::new(Mutex::new(vec![]));
+    let log = received.clone();
+
+    thread::spawn(move || {
+        for (count, mut request) in server.incoming_requests().enumerate() {
+            if count < failures {
+                request
+                    .respond(Response::from_string("busy").with_status_code(503))
+                    .unwrap();
+                continue;
+            }
+            let signature = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv(SIGNATURE_HEADER))
+                .map(|header| header.value.to_string())
+                .unwrap_or_default();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            log.lock().unwrap().push((signature, body));
+            request.respond(Response::from_string("ok")).unwrap();
+        }
+    });
+
+    (url, received)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use super::{mock_receiver, sign, Webhook};
+
+    #[test]
+    fn signs_and_retries() -> Result<()> {
+        let (url, received) = mock_receiver(2);
+        let mut webhook = Webhook::new(&url);
+        webhook.secret = Some("hunter2".to_string());
+        webhook.backoff = Duration::from_millis(1);
+
+        webhook.send(&json!({"event": "payment.seen", "amount": 1000}))?;
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (signature, body) = &received[0];
+        assert_eq!(signature, &format!("sha256={}", sign("hunter2", body)));
+
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let (url, _) = mock_receiver(10);
+        let mut webhook = Webhook::new(&url);
+        webhook.retries = 1;
+        webhook.backoff = Duration::from_millis(1);
+        assert!(webhook.send(&json!({})).is_err());
+
+        Ok(())
+    }
+}
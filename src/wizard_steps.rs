@@ -1,8 +1,9 @@
-use std::ffi::OsStr;
 use std::{
     fs,
     path::{Path, PathBuf},
     str::FromStr,
+    thread,
+    time::Duration,
 };
 
 use anyhow::{bail, Result};
@@ -10,45 +11,82 @@ use bdk::bitcoin::{self, util::bip32::ExtendedPubKey, Address, Network};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use slip132::FromSlip132;
 
-use crate::{util, util::build_descriptor, ColdcardJson, Desc, Factory};
+use crate::{
+    alert::{self, RefillAlert},
+    export,
+    export::ExportOptions,
+    history, manifest,
+    purge::{self, PurgeOptions},
+    server, site,
+    site::SiteOptions,
+    store::{SqlDialect, SqlDump},
+    template, util,
+    util::build_descriptor,
+    verify_address_proof, verify_database, wallet_hints,
+    watch::{self, WatchOptions},
+    watchonly, ColdcardJson, Database, Desc, Factory, GenerateOptions, Keyring, Store,
+};
 
 pub enum Mode {
     Coldcard,
     Generic,
 }
 
-/// Purge config and database files
-pub fn purge(config_dir: &Path) -> Result<()> {
+/// Purge the config file and the databases Address Factory made in this folder
+pub fn purge(config_dir: &Path, options: PurgeOptions) -> Result<()> {
     let theme = ColorfulTheme::default();
+    let config_file = config_dir.join("address-factory.json");
+    let registry = if config_file.is_file() {
+        match Factory::from_path(config_file.clone()) {
+            Ok(factory) => factory.batches,
+            Err(error) => {
+                eprintln!(
+                    "Couldn't read the batch history, only looking in this folder: {}",
+                    error
+                );
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+    let files = purge::files_to_purge(&config_file, Path::new("."), &registry)?;
 
-    println!(
-        "Are you sure you want to delete your config file and all the *.db files in this folder?"
-    );
-    println!("Cannot be undone!");
+    if files.is_empty() {
+        println!("Nothing to purge");
+        return Ok(());
+    }
+
+    match &options.archive {
+        Some(archive) => println!(
+            "These files will be moved into {}:",
+            archive.to_string_lossy()
+        ),
+        None => println!("These files will be deleted:"),
+    }
+    for file in &files {
+        println!("  {}", file.to_string_lossy());
+    }
+
+    if options.dry_run {
+        println!("Dry run, nothing was touched");
+        return Ok(());
+    }
+
+    if options.archive.is_none() {
+        println!("Cannot be undone!");
+    }
     if Confirm::with_theme(&theme)
         .with_prompt("Purge")
         .interact()?
     {
         println!("Purging...");
-        // Delete the config file and its folder
-        if config_dir.exists() {
-            println!("Deleting address-factory.json");
-            fs::remove_dir_all(config_dir)?;
-        }
-
-        // Delete all the *.db files in this folder
-        for entry in fs::read_dir(".")? {
-            let entry = entry?;
-            let path = entry.path();
-
-            if path.is_file() {
-                let extension = path.extension().unwrap_or_else(|| OsStr::new("nope"));
-                if extension == "db" {
-                    println!("Deleting {:?}", path);
-                    fs::remove_file(path)?;
-                }
-            }
+        match purge::remove_or_archive(&files, options.archive.as_deref())? {
+            Some(folder) => println!("Moved them to {}", folder.to_string_lossy()),
+            None => println!("Deleted them"),
         }
+        // Only drops the config folder if nothing else is in it
+        let _ = fs::remove_dir(config_dir);
     } else {
         println!("Purge canceled");
     }
@@ -65,7 +103,7 @@ pub fn select_mode() -> Result<Mode> {
         .default(0)
         .items(&modes[..])
         .interact()?;
-    println!("");
+    println!();
 
     if mode_choice == 0 {
         Ok(Mode::Coldcard)
@@ -85,7 +123,7 @@ pub fn select_network() -> Result<Network> {
         .default(1)
         .items(&network_selections[..])
         .interact()?;
-    println!("");
+    println!();
     let network = bitcoin::Network::from_str(network_selections[network_choice])?;
 
     Ok(network)
@@ -127,7 +165,7 @@ pub fn new_generic(network: Network) -> Result<Desc> {
     if xpub.network != network {
         bail!("This extended public key doesn't match the network you selected.")
     }
-    println!("");
+    println!();
 
     // STEP 2: derivation path
     println!("A derivation looks something like m/84'/1'/0'");
@@ -143,7 +181,7 @@ pub fn new_generic(network: Network) -> Result<Desc> {
     if derivation_path.len() != 3 {
         bail!("That derivation path doesn't have the correct length.")
     }
-    println!("");
+    println!();
 
     let descriptor = build_descriptor(xpub, derivation_path)?;
 
@@ -159,7 +197,7 @@ pub fn check_first_address(descriptor: Desc, network: Network) -> Result<Address
         .with_prompt("Enter your wallet's first address")
         .interact()?;
 
-    println!("");
+    println!();
     let address = Address::from_str(&first_address)?;
 
     util::check_address(descriptor, network, address, 0)
@@ -180,7 +218,7 @@ pub fn new_factory(
         .default(number_to_generate)
         .show_default(true)
         .interact()?;
-    println!("");
+    println!();
 
     println!("How many addresses to skip (because you've used them before)");
     let skip_num: u32 = Input::with_theme(&theme)
@@ -188,13 +226,18 @@ pub fn new_factory(
         .default(next_index)
         .show_default(true)
         .interact()?;
-    println!("");
+    println!();
+    warn_about_gap_limit(skip_num, number_to_generate);
 
     println!("Enter a short message to be signed with the address");
+    println!(
+        "It can use {{{}}}. Without {{address}} the address goes on the end.",
+        template::PLACEHOLDERS.join("}, {")
+    );
     let message: String = Input::with_theme(&theme)
         .with_prompt("Message")
         .interact()?;
-    println!("");
+    println!();
 
     let factory = Factory::new(
         descriptor.to_string(),
@@ -215,27 +258,309 @@ pub fn load_and_edit_factory(path_to_config: PathBuf) -> Result<Factory> {
     let mut factory = Factory::from_path(path_to_config.clone())?;
 
     println!("{}", factory);
-    println!("");
+    println!();
+    warn_about_gap_limit(factory.next_index, factory.number_to_generate);
 
     // TODO: if they put in the wrong skip_num I think the index will get screwed up?
     if Confirm::with_theme(&theme)
         .with_prompt("Do you want to make any changes?")
         .interact()?
     {
-        factory = new_factory(
+        let mut edited = new_factory(
             factory.descriptor,
             factory.network,
             factory.next_index,
             factory.number_to_generate,
             path_to_config.parent().unwrap().to_path_buf(),
-        )?
+        )?;
+        // Keep what the prompts don't ask about
+        edited.encrypted = factory.encrypted;
+        edited.batches = factory.batches;
+        factory = edited;
     }
 
     Ok(factory)
 }
 
+/// Let people know their wallet won't see payments to the whole batch out of the box
+fn warn_about_gap_limit(next_index: u32, number_to_generate: u32) {
+    let warnings = wallet_hints::gap_limit_warnings(next_index, number_to_generate);
+    if warnings.is_empty() {
+        return;
+    }
+
+    println!("Heads up:");
+    for warning in warnings {
+        println!("  {}", warning);
+    }
+    println!("You'll get instructions for raising it once the addresses are generated.");
+    println!();
+}
+
+/// Serve addresses from a signed addresses database over HTTP
+pub fn serve(database: &Path, bind: &str, alert: RefillAlert) -> Result<()> {
+    let db = Database::open(database)?;
+    server::serve(db, bind, alert)
+}
+
+/// Print how much of the pool is left, and raise the alert if it's low
+pub fn status(database: &Path, alert: RefillAlert) -> Result<()> {
+    let db = Database::open_read_only(database)?;
+    let stats = db.stats()?;
+    println!("{}", db.filename);
+    println!("Batches: {}", db.batches()?.len());
+    println!("Addresses: {}", stats.total);
+    println!("Unused: {}", stats.unused);
+    println!("Dispensed, waiting on payment: {}", stats.dispensed);
+    println!("Paid: {}", stats.paid);
+    if stats.expired > 0 {
+        println!("Expired, won't be handed out: {}", stats.expired);
+    }
+
+    let level = db.pool_level(alert.low_water)?;
+    if level.low {
+        println!();
+        println!("{}", alert::refill_message(&db.filename, &level));
+        alert.fire(&db.filename, &level)?;
+    }
+    Ok(())
+}
+
+/// Record payments to the database's addresses, once or every `interval` seconds
+pub fn watch(
+    database: &Path,
+    backend: &str,
+    options: WatchOptions,
+    interval: Option<u64>,
+) -> Result<()> {
+    let mut db = Database::open(database)?;
+    let backend = watch::connect(backend)?;
+
+    loop {
+        match watch::watch(&mut db, backend.as_ref(), &options) {
+            Ok(report) => println!("{}", report),
+            // A flaky backend shouldn't stop a long running watch
+            Err(error) if interval.is_some() => eprintln!("Couldn't check payments: {}", error),
+            Err(error) => return Err(error),
+        }
+
+        match interval {
+            Some(seconds) => thread::sleep(Duration::from_secs(seconds)),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Render a signed addresses database as a static site
+pub fn export_site(database: &Path, out: &Path, options: SiteOptions) -> Result<()> {
+    let db = Database::open_read_only(database)?;
+    let count = site::export_site(&db, out, &options)?;
+    println!(
+        "Wrote {} addresses to {}. Upload that folder to any static host.",
+        count,
+        out.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Write a database's addresses out for other systems
+pub fn export(
+    path_to_config: PathBuf,
+    database: &Path,
+    out: &Path,
+    mut options: ExportOptions,
+) -> Result<()> {
+    let db = Database::open_read_only(database)?;
+    if path_to_config.exists() {
+        options.factory_message = Some(Factory::from_path(path_to_config)?.message);
+    }
+    let count = export::export(&db, out, &options)?;
+    println!("Wrote {} addresses to {}", count, out.to_string_lossy());
+    if options.signatures {
+        println!(
+            "Wrote their signatures to {}",
+            export::signatures_dir(out).to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+/// Write watch-only wallet files covering every address the factory has handed out
+pub fn export_watchonly(path_to_config: PathBuf, out: &Path, timestamp: &str) -> Result<()> {
+    let factory = Factory::from_path(path_to_config)?;
+    let timestamp = match timestamp {
+        "now" => serde_json::json!("now"),
+        unix => match unix.parse::<u64>() {
+            Ok(unix) => serde_json::json!(unix),
+            Err(_) => bail!("--timestamp should be \"now\" or a UNIX time, not {}", unix),
+        },
+    };
+
+    for path in watchonly::export_watchonly(&factory, out, &timestamp)? {
+        println!("Wrote {}", path.to_string_lossy());
+    }
+    println!(
+        "They watch indexes 0 to {} of {}",
+        factory.next_index.saturating_sub(1),
+        factory.descriptor
+    );
+    Ok(())
+}
+
+/// List every batch the factory has written and anything wrong with them
+pub fn history(path_to_config: PathBuf) -> Result<()> {
+    let factory = Factory::from_path(path_to_config)?;
+    if factory.batches.is_empty() {
+        println!("No batches recorded yet");
+        return Ok(());
+    }
+
+    for (i, record) in factory.batches.iter().enumerate() {
+        println!(
+            "{}. {} indexes {} to {} in {}",
+            i + 1,
+            record.created_at,
+            record.start_index,
+            record.last_index(),
+            record.file.to_string_lossy()
+        );
+        println!(
+            "   Signed by {}: {}",
+            record.signing_key_fingerprint, record.message
+        );
+        println!("   SHA-256 {}", record.sha256);
+        if let Some(valid_until) = &record.valid_until {
+            println!("   Valid until {}", valid_until);
+        }
+    }
+    println!();
+
+    let problems = history::problems(&factory.batches)?;
+    if problems.is_empty() {
+        println!("No overlapping ranges or missing files");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    bail!("The batch history has problems")
+}
+
+/// Open the database to append to, or create a new one at `output`.
+/// `output` can be a file or a directory to put a timestamped file in.
+pub fn open_database(append: Option<PathBuf>, output: Option<PathBuf>) -> Result<Database> {
+    match (append, output) {
+        (Some(path), _) => {
+            println!("Appending to {}", path.to_string_lossy());
+            Database::open(&path)
+        }
+        (None, Some(path)) if path.is_dir() => Database::create(&Database::default_path(&path)),
+        (None, Some(path)) => Database::create(&path),
+        (None, None) => Database::create(&Database::default_path(Path::new("."))),
+    }
+}
+
+/// Where to put the next batch: a SQL file to load elsewhere, or a SQLite database
+pub fn open_store(
+    append: Option<PathBuf>,
+    output: Option<PathBuf>,
+    sql_dump: Option<PathBuf>,
+    dialect: SqlDialect,
+) -> Result<Box<dyn Store>> {
+    match sql_dump {
+        Some(path) => Ok(Box::new(SqlDump::create(&path, dialect)?)),
+        None => Ok(Box::new(open_database(append, output)?)),
+    }
+}
+
 /// Run program to generate addresses, sign them and put them into a database
-pub fn execute(factory: &mut Factory) -> Result<()> {
-    factory.generate_addresses()?;
+pub fn execute(
+    factory: &mut Factory,
+    store: &mut dyn Store,
+    options: GenerateOptions,
+) -> Result<()> {
+    let start_index = factory.next_index;
+    factory.generate_addresses(store, &options)?;
+
+    println!();
+    println!(
+        "{}",
+        wallet_hints::import_hints(
+            &factory.descriptor,
+            factory.network,
+            start_index,
+            factory.number_to_generate
+        )
+    );
+    Ok(())
+}
+
+/// Check a single address and its proof against a signed Merkle root
+pub fn verify_address(
+    address: &str,
+    index: u32,
+    proof: &str,
+    signed_root: &Path,
+    public_key: &Path,
+) -> Result<()> {
+    let keyring = Keyring::from_public_key(public_key)?;
+    let signed_root = fs::read_to_string(signed_root)?;
+
+    let statement = verify_address_proof(address, index, proof, &signed_root, &keyring)?;
+    println!("{}", statement);
+    println!();
+    println!("{} at index {} is under this signed root", address, index);
+    Ok(())
+}
+
+/// Check a manifest's signature and that its database hasn't changed since
+pub fn verify_manifest(path: &Path, public_key: &Path) -> Result<()> {
+    let keyring = Keyring::from_public_key(public_key)?;
+    let manifest = manifest::verify_manifest(path, &keyring)?;
+
+    println!(
+        "{} matches its signed manifest: {} on {}, SHA-256 {}",
+        manifest.file, manifest.descriptor_checksum, manifest.network, manifest.sha256
+    );
+    for batch in &manifest.batches {
+        println!(
+            "Batch {}: indexes {}..={} ({} addresses) {} to {}",
+            batch.id,
+            batch.first_index,
+            batch.last_index,
+            batch.count,
+            batch.first_address,
+            batch.last_address
+        );
+    }
+    Ok(())
+}
+
+/// Re-derive and check every row of a signed address database against the factory
+pub fn verify(
+    path_to_config: PathBuf,
+    database: &Path,
+    public_key: &Path,
+    message: Option<String>,
+) -> Result<()> {
+    let factory = Factory::from_path(path_to_config)?;
+    let db = Database::open_read_only(database)?;
+    let keyring = Keyring::from_public_key(public_key)?;
+    let message = message.unwrap_or(factory.message);
+
+    println!("Verifying {}...", db.filename);
+    let report = verify_database(
+        &db,
+        factory.descriptor,
+        factory.network,
+        &message,
+        &factory.batches,
+        &keyring,
+    )?;
+    println!("{}", report);
+
+    if !report.is_ok() {
+        bail!("{} failed verification", db.filename)
+    }
     Ok(())
 }